@@ -0,0 +1,88 @@
+//! Decode defmt frames sent by firmware built with the `defmt-usb` feature.
+//!
+//! Usage: `decode-defmt <elf> <path>`, where `<elf>` is the firmware the board
+//! is running and `<path>` is the CDC ACM port (for example `/dev/ttyACM0` or
+//! `\\.\COM3`), a file with captured output or `-` for stdin.
+
+use std::fs;
+use std::io;
+
+use defmt_decoder::{DecodeError, Frame, Locations, Table};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [elf_path, path] = args.as_slice() else {
+        eprintln!("usage: decode-defmt <elf> <port or file, - for stdin>");
+        std::process::exit(2);
+    };
+    let elf = match fs::read(elf_path) {
+        Ok(elf) => elf,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", elf_path, e);
+            std::process::exit(1);
+        }
+    };
+    let table = match Table::parse(&elf) {
+        Ok(Some(table)) => table,
+        Ok(None) => {
+            eprintln!("{} contains no defmt data", elf_path);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("failed to parse {}: {}", elf_path, e);
+            std::process::exit(1);
+        }
+    };
+    // Without debug info the frames are still decoded, just without locations.
+    let locations = table.get_locations(&elf).unwrap_or_default();
+    let mut input = match debug::input::open(path) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("failed to open {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut decoder = table.new_stream_decoder();
+    let mut buf = [0; 1024];
+    loop {
+        let len = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                eprintln!("read failed: {}", e);
+                break;
+            }
+        };
+        decoder.received(&buf[..len]);
+        loop {
+            match decoder.decode() {
+                Ok(frame) => println!("{}", format_frame(&frame, &locations)),
+                Err(DecodeError::UnexpectedEof) => break,
+                // Frames are zero terminated, so the decoder resynchronizes on
+                // the next one.
+                Err(DecodeError::Malformed) => eprintln!("malformed frame skipped"),
+            }
+        }
+    }
+}
+
+/// Lay out a frame like the firmware's verbose style:
+/// `[12.345678 INFO  main:118] portb = 00000000`.
+///
+/// Console output has no level and is printed as is.
+fn format_frame(frame: &Frame, locations: &Locations) -> String {
+    let Some(level) = frame.level() else {
+        return frame.display_message().to_string();
+    };
+    let mut header = String::from("[");
+    if let Some(timestamp) = frame.display_timestamp() {
+        header += &format!("{} ", timestamp);
+    }
+    header += &format!("{:<5}", level.as_str().to_uppercase());
+    if let Some(location) = locations.get(&frame.index()) {
+        header += &format!(" {}:{}", location.module, location.line);
+    }
+    format!("{}] {}", header, frame.display_message())
+}
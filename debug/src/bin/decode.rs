@@ -0,0 +1,41 @@
+//! Decode COBS-framed binary logs from the device.
+//!
+//! Usage: `decode <path>`, where `<path>` is the CDC ACM port (for example
+//! `/dev/ttyACM0` or `\\.\COM3`), a file with captured output or `-` for
+//! stdin.
+
+use std::io::{self, BufReader};
+
+use debug::frame::{FrameReader, Item};
+
+fn main() {
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: decode <port or file, - for stdin>");
+            std::process::exit(2);
+        }
+    };
+    let input = match debug::input::open(&path) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("failed to open {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    for item in FrameReader::new(BufReader::new(input)) {
+        match item {
+            Ok(Item::Frame(frame)) => println!("{}", frame),
+            Ok(Item::Lost(n)) => println!("[{} frames lost]", n),
+            Ok(Item::Invalid(bytes, e)) => {
+                eprintln!("{} ({} bytes): {:02x?}", e, bytes.len(), bytes)
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => {
+                eprintln!("read failed: {}", e);
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Decoder for the COBS-framed binary log records sent by the firmware's
+//! `Encoding::Cobs` mode. See `src/frame.rs` in the firmware for the layout.
+
+use std::fmt;
+use std::io::{self, BufRead};
+
+/// The newest frame version this decoder understands. Version 1 frames, which
+/// have no core byte, are still accepted.
+pub const VERSION: u8 = 2;
+
+/// A decoded log record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub seq: u32,
+    pub timestamp_us: u64,
+    /// `0` for console output, `1` error to `5` trace.
+    pub level: u8,
+    /// The core the record was logged on, `0` for version 1 frames.
+    pub core: u8,
+    pub target: String,
+    pub message: String,
+}
+
+impl Frame {
+    /// The name of the frame's level.
+    pub fn level_name(&self) -> &'static str {
+        match self.level {
+            0 => "CONSOLE",
+            1 => "ERROR",
+            2 => "WARN",
+            3 => "INFO",
+            4 => "DEBUG",
+            5 => "TRACE",
+            _ => "?",
+        }
+    }
+
+    /// Parse a frame from its COBS encoded bytes, without the terminating zero.
+    pub fn decode(encoded: &[u8]) -> Result<Frame, DecodeError> {
+        let raw = cobs_decode(encoded).ok_or(DecodeError::Cobs)?;
+        let header_len = match raw.first() {
+            None => return Err(DecodeError::TooShort),
+            Some(1) => 15,
+            Some(2) => 16,
+            Some(&v) => return Err(DecodeError::Version(v)),
+        };
+        if raw.len() < header_len {
+            return Err(DecodeError::TooShort);
+        }
+        let target_end = header_len + raw[header_len - 1] as usize;
+        if raw.len() < target_end {
+            return Err(DecodeError::TooShort);
+        }
+        Ok(Frame {
+            seq: u32::from_le_bytes(raw[1..5].try_into().unwrap()),
+            timestamp_us: u64::from_le_bytes(raw[5..13].try_into().unwrap()),
+            level: raw[13],
+            core: if header_len == 16 { raw[14] } else { 0 },
+            target: String::from_utf8_lossy(&raw[header_len..target_end]).into_owned(),
+            message: String::from_utf8_lossy(&raw[target_end..]).into_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = self.timestamp_us / 1000;
+        write!(
+            f,
+            "[{}.{:03} c{} {:<5} {}] {}",
+            ms / 1000,
+            ms % 1000,
+            self.core,
+            self.level_name(),
+            self.target,
+            self.message
+        )
+    }
+}
+
+/// Errors returned when decoding a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The bytes are not valid COBS.
+    Cobs,
+    /// The frame is shorter than its header says.
+    TooShort,
+    /// The frame has an unknown version.
+    Version(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Cobs => write!(f, "invalid COBS encoding"),
+            DecodeError::TooShort => write!(f, "frame too short"),
+            DecodeError::Version(v) => write!(f, "unknown frame version {}", v),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decode a COBS block sequence, without the terminating zero.
+pub fn cobs_decode(src: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(src.len());
+    let mut i = 0;
+    while i < src.len() {
+        let code = src[i] as usize;
+        if code == 0 || i + code > src.len() {
+            return None;
+        }
+        out.extend_from_slice(&src[i + 1..i + code]);
+        i += code;
+        if code < 0xff && i < src.len() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+/// Reads zero terminated frames from a byte stream.
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    next_seq: Option<u32>,
+    pending: Option<Frame>,
+}
+
+/// An item read from the stream.
+#[derive(Debug)]
+pub enum Item {
+    Frame(Frame),
+    /// Frames were skipped according to the sequence numbers.
+    Lost(u32),
+    /// Bytes that did not decode as a frame.
+    Invalid(Vec<u8>, DecodeError),
+}
+
+impl<R: BufRead> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        FrameReader {
+            inner,
+            buf: Vec::new(),
+            next_seq: None,
+            pending: None,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for FrameReader<R> {
+    type Item = io::Result<Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(frame) = self.pending.take() {
+            return Some(Ok(Item::Frame(frame)));
+        }
+        loop {
+            // A partial frame stays in `buf` across errors such as read
+            // timeouts until the rest of it arrives.
+            match self.inner.read_until(0, &mut self.buf) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            if self.buf.last() != Some(&0) {
+                continue;
+            }
+            let mut encoded = std::mem::take(&mut self.buf);
+            encoded.pop();
+            if encoded.is_empty() {
+                continue;
+            }
+            let frame = match Frame::decode(&encoded) {
+                Ok(frame) => frame,
+                Err(e) => return Some(Ok(Item::Invalid(encoded, e))),
+            };
+            let expected = self.next_seq.replace(frame.seq.wrapping_add(1));
+            if let Some(expected) = expected {
+                let lost = frame.seq.wrapping_sub(expected);
+                // A reset of the device starts the sequence over, so only
+                // forward jumps are counted as lost frames.
+                if lost != 0 && lost < u32::MAX / 2 {
+                    self.pending = Some(frame);
+                    return Some(Ok(Item::Lost(lost)));
+                }
+            }
+            return Some(Ok(Item::Frame(frame)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame as written by the firmware's `frame::encode`, with sequence
+    /// number 0x01020304 at info level on core 0, 19 µs after boot.
+    const FIRMWARE_FRAME: &[u8] = &[
+        7, 2, 4, 3, 2, 1, 19, 1, 1, 1, 1, 1, 1, 2, 3, 14, 3, 109, 99, 112, 112, 111, 114, 116, 98,
+        32, 61, 32, 48, 0,
+    ];
+
+    /// COBS encode `src` and terminate it, as the firmware does.
+    fn cobs_encode(src: &[u8]) -> Vec<u8> {
+        let mut out = vec![0];
+        let mut code_idx = 0;
+        let mut code = 1u8;
+        for &b in src {
+            if b != 0 {
+                out.push(b);
+                code += 1;
+            }
+            if b == 0 || code == 0xff {
+                out[code_idx] = code;
+                code_idx = out.len();
+                out.push(0);
+                code = 1;
+            }
+        }
+        out[code_idx] = code;
+        out.push(0);
+        out
+    }
+
+    /// A version 2 frame in the layout of the firmware's `src/frame.rs`.
+    fn encode(seq: u32, level: u8, core: u8, target: &str, message: &str) -> Vec<u8> {
+        let mut raw = vec![VERSION];
+        raw.extend_from_slice(&seq.to_le_bytes());
+        raw.extend_from_slice(&1_234_567u64.to_le_bytes());
+        raw.extend_from_slice(&[level, core, target.len() as u8]);
+        raw.extend_from_slice(target.as_bytes());
+        raw.extend_from_slice(message.as_bytes());
+        cobs_encode(&raw)
+    }
+
+    fn decode(frame: &[u8]) -> Result<Frame, DecodeError> {
+        Frame::decode(&frame[..frame.len() - 1])
+    }
+
+    fn read(stream: &[u8]) -> Vec<Item> {
+        FrameReader::new(stream).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn firmware_frame_is_decoded() {
+        let frame = decode(FIRMWARE_FRAME).unwrap();
+        assert_eq!(
+            frame,
+            Frame {
+                seq: 0x0102_0304,
+                timestamp_us: 19,
+                level: 3,
+                core: 0,
+                target: "mcp".into(),
+                message: "portb = 0".into(),
+            }
+        );
+        assert_eq!(frame.to_string(), "[0.000 c0 INFO  mcp] portb = 0");
+    }
+
+    #[test]
+    fn frame_round_trips() {
+        // Zero bytes and a run longer than one COBS block.
+        let message = format!("a\0b{}", "x".repeat(300));
+        let frame = decode(&encode(7, 4, 1, "main", &message)).unwrap();
+        assert_eq!(frame.seq, 7);
+        assert_eq!(frame.timestamp_us, 1_234_567);
+        assert_eq!(frame.level_name(), "DEBUG");
+        assert_eq!(frame.core, 1);
+        assert_eq!(frame.target, "main");
+        assert_eq!(frame.message, message);
+    }
+
+    #[test]
+    fn version_1_frame_has_no_core() {
+        let mut raw = vec![1];
+        raw.extend_from_slice(&5u32.to_le_bytes());
+        raw.extend_from_slice(&0u64.to_le_bytes());
+        raw.extend_from_slice(&[2, 1]);
+        raw.extend_from_slice(b"tmsg");
+        let frame = decode(&cobs_encode(&raw)).unwrap();
+        assert_eq!((frame.seq, frame.level, frame.core), (5, 2, 0));
+        assert_eq!(
+            (frame.target.as_str(), frame.message.as_str()),
+            ("t", "msg")
+        );
+    }
+
+    #[test]
+    fn invalid_frames_are_rejected() {
+        // A block that runs past the end, and a zero code byte.
+        assert_eq!(Frame::decode(&[5, 2, 1]), Err(DecodeError::Cobs));
+        assert_eq!(Frame::decode(&[0, 2]), Err(DecodeError::Cobs));
+        // Cut off in the header.
+        let frame = encode(1, 3, 0, "main", "hello");
+        assert_eq!(Frame::decode(&frame[..10]), Err(DecodeError::TooShort));
+        // The target is longer than what is left.
+        let mut raw = vec![VERSION];
+        raw.extend_from_slice(&[1; 14]);
+        raw.push(20);
+        raw.extend_from_slice(b"short");
+        assert_eq!(decode(&cobs_encode(&raw)), Err(DecodeError::TooShort));
+        assert_eq!(
+            decode(&cobs_encode(&[3, 1, 2])),
+            Err(DecodeError::Version(3))
+        );
+    }
+
+    #[test]
+    fn sequence_gap_is_reported_before_the_frame() {
+        let stream = [
+            encode(1, 3, 0, "t", "one"),
+            encode(2, 3, 0, "t", "two"),
+            encode(6, 3, 0, "t", "six"),
+        ]
+        .concat();
+        let items = read(&stream);
+        assert_eq!(items.len(), 4);
+        assert!(matches!(&items[2], Item::Lost(3)));
+        assert!(matches!(&items[3], Item::Frame(f) if f.message == "six"));
+    }
+
+    #[test]
+    fn restarted_sequence_is_not_a_loss() {
+        let stream = [
+            encode(40, 3, 0, "t", "before"),
+            encode(0, 3, 0, "t", "after"),
+        ]
+        .concat();
+        let items = read(&stream);
+        assert_eq!(items.len(), 2);
+        assert!(items.iter().all(|item| matches!(item, Item::Frame(_))));
+    }
+
+    #[test]
+    fn reader_recovers_after_an_invalid_frame() {
+        let stream = [vec![0], vec![9, 1, 2, 0], encode(1, 3, 0, "t", "ok")].concat();
+        let items = read(&stream);
+        assert_eq!(items.len(), 2);
+        assert!(
+            matches!(&items[0], Item::Invalid(bytes, DecodeError::Cobs) if bytes == &[9, 1, 2])
+        );
+        assert!(matches!(&items[1], Item::Frame(f) if f.message == "ok"));
+    }
+}
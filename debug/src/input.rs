@@ -0,0 +1,28 @@
+//! Opening what the decoders read from.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::time::Duration;
+
+/// How long a read from the port waits before reporting a timeout.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Open `path` for reading: stdin for `-`, a regular file such as a capture
+/// as it is, and anything else as a serial port.
+///
+/// Ports are opened raw through `serialport`, as a tty in its default cooked
+/// mode would turn `\r` into `\n` inside binary frames and echo every byte
+/// back to the device. Reads from a port time out with `TimedOut` while the
+/// device is quiet.
+pub fn open(path: &str) -> io::Result<Box<dyn Read>> {
+    if path == "-" {
+        return Ok(Box::new(io::stdin()));
+    }
+    if fs::metadata(path).is_ok_and(|m| m.is_file()) {
+        return Ok(Box::new(File::open(path)?));
+    }
+    let port = serialport::new(path, 115_200)
+        .timeout(READ_TIMEOUT)
+        .open()?;
+    Ok(Box::new(port))
+}
@@ -0,0 +1,5 @@
+//! Host side helpers for talking to the rp2040 logger.
+
+pub mod frame;
+pub mod input;
+pub mod monitor;
@@ -4,6 +4,11 @@ const SENDER_ADDR: &str = "0.0.0.0:9932";
 const BROADCAST_ADDR: &str = "255.255.255.255:8080";
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("monitor") {
+        debug::monitor::main(&args[1..]);
+        return;
+    }
     {
         match UdpSocket::bind(SENDER_ADDR) {
             Ok(sock) => {
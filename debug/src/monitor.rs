@@ -0,0 +1,518 @@
+//! Monitor for the logger's CDC ACM port.
+//!
+//! Reconnects whenever the board resets or re-enumerates, colours and filters
+//! records by level or target, and tees everything it shows into rotating log
+//! files named after the time they were opened.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::frame::{FrameReader, Item};
+
+const USAGE: &str = "usage: debug monitor <port> [--level <level>] [--target <prefix>] \
+[--log-dir <dir>] [--max-size <bytes>] [--keep <files>] [--cobs] [--no-color]";
+
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A log level as printed by the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        match s.to_ascii_uppercase().as_str() {
+            "ERROR" => Some(Level::Error),
+            "WARN" => Some(Level::Warn),
+            "INFO" => Some(Level::Info),
+            "DEBUG" => Some(Level::Debug),
+            "TRACE" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn from_frame(level: u8) -> Option<Level> {
+        match level {
+            1 => Some(Level::Error),
+            2 => Some(Level::Warn),
+            3 => Some(Level::Info),
+            4 => Some(Level::Debug),
+            5 => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Level::Error => "\x1b[31m",
+            Level::Warn => "\x1b[33m",
+            Level::Info => "\x1b[32m",
+            Level::Debug => "\x1b[34m",
+            Level::Trace => "\x1b[90m",
+        }
+    }
+}
+
+/// Command line options of the monitor.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub port: String,
+    /// Hide records less severe than this.
+    pub level: Option<Level>,
+    /// Hide records whose target does not start with this.
+    ///
+    /// Only the verbose style and COBS frames carry a target, so records in
+    /// the other styles are always shown.
+    pub target: Option<String>,
+    pub log_dir: Option<PathBuf>,
+    /// Start a new log file once the current one is this large.
+    pub max_size: u64,
+    /// The number of log files to keep in `log_dir`.
+    pub keep: usize,
+    /// Decode COBS-framed binary records instead of text lines.
+    pub cobs: bool,
+    pub color: bool,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut args = args.iter();
+        let mut opts = Options {
+            port: String::new(),
+            level: None,
+            target: None,
+            log_dir: None,
+            max_size: 10 * 1024 * 1024,
+            keep: 10,
+            cobs: false,
+            color: true,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--level" => {
+                    let v = value()?;
+                    opts.level = Some(Level::parse(v).ok_or_else(|| format!("bad level {}", v))?);
+                }
+                "--target" => opts.target = Some(value()?.clone()),
+                "--log-dir" => opts.log_dir = Some(PathBuf::from(value()?)),
+                "--max-size" => {
+                    let v = value()?;
+                    opts.max_size = v.parse().map_err(|_| format!("bad size {}", v))?;
+                }
+                "--keep" => {
+                    let v = value()?;
+                    opts.keep = v.parse().map_err(|_| format!("bad count {}", v))?;
+                }
+                "--cobs" => opts.cobs = true,
+                "--no-color" => opts.color = false,
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ if opts.port.is_empty() => opts.port = arg.clone(),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+        if opts.port.is_empty() {
+            return Err("missing port".into());
+        }
+        Ok(opts)
+    }
+}
+
+/// Parse the options and monitor the port until the process is killed.
+pub fn main(args: &[String]) {
+    let opts = match Options::parse(args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let mut monitor = Monitor {
+        log: opts
+            .log_dir
+            .clone()
+            .map(|dir| RotatingLog::new(dir, opts.max_size, opts.keep)),
+        opts,
+        untargeted: false,
+    };
+    monitor.run();
+}
+
+/// A single line to show, with whatever metadata could be recovered.
+struct Line {
+    level: Option<Level>,
+    target: Option<String>,
+    text: String,
+}
+
+impl Line {
+    /// Recover level and target from the `[uptime LEVEL target:line]` header
+    /// of the text styles.
+    fn parse(text: String) -> Line {
+        let header = text
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once(']'))
+            .map(|(header, _)| header);
+        let mut level = None;
+        let mut target = None;
+        for word in header.into_iter().flat_map(str::split_whitespace) {
+            if let Some(l) = Level::parse(word) {
+                level = Some(l);
+            } else if let Some((t, _line)) = word.rsplit_once(':') {
+                target = Some(t.to_string());
+            }
+        }
+        Line {
+            level,
+            target,
+            text,
+        }
+    }
+}
+
+struct Monitor {
+    opts: Options,
+    log: Option<RotatingLog>,
+    /// Whether a record without a target was seen while `--target` is set.
+    untargeted: bool,
+}
+
+impl Monitor {
+    fn run(&mut self) -> ! {
+        let mut waiting = false;
+        loop {
+            let port = serialport::new(&self.opts.port, 115_200)
+                .timeout(Duration::from_millis(100))
+                .open();
+            match port {
+                Ok(port) => {
+                    waiting = false;
+                    self.status(&format!("connected to {}", self.opts.port));
+                    let reader = BufReader::new(port);
+                    let result = if self.opts.cobs {
+                        self.read_frames(reader)
+                    } else {
+                        self.read_lines(reader)
+                    };
+                    let reason = match result {
+                        Ok(()) => "port closed".to_string(),
+                        Err(e) => e.to_string(),
+                    };
+                    self.status(&format!("disconnected: {}", reason));
+                }
+                Err(e) => {
+                    if !waiting {
+                        self.status(&format!("waiting for {}: {}", self.opts.port, e));
+                        waiting = true;
+                    }
+                }
+            }
+            sleep(RECONNECT_INTERVAL);
+        }
+    }
+
+    fn read_lines(&mut self, mut reader: impl BufRead) -> io::Result<()> {
+        let mut buf = Vec::new();
+        loop {
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) if buf.ends_with(b"\n") => {
+                    let text = String::from_utf8_lossy(&buf).trim_end().to_string();
+                    buf.clear();
+                    self.show(Line::parse(text));
+                }
+                Ok(_) => {}
+                // A partial line stays in `buf` until the rest arrives.
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read_frames(&mut self, reader: impl BufRead) -> io::Result<()> {
+        for item in FrameReader::new(reader) {
+            match item {
+                Ok(Item::Frame(frame)) => self.show(Line {
+                    level: Level::from_frame(frame.level),
+                    target: Some(frame.target.clone()),
+                    text: frame.to_string(),
+                }),
+                Ok(Item::Lost(n)) => self.status(&format!("{} frames lost", n)),
+                Ok(Item::Invalid(bytes, e)) => {
+                    self.status(&format!("{} ({} bytes)", e, bytes.len()))
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn show(&mut self, line: Line) {
+        // Lines without a level, such as console output, are always shown.
+        if let (Some(min), Some(level)) = (self.opts.level, line.level) {
+            if level > min {
+                return;
+            }
+        }
+        if let Some(prefix) = &self.opts.target {
+            match &line.target {
+                Some(target) if !target.starts_with(prefix.as_str()) => return,
+                Some(_) => {}
+                None if line.level.is_some() && !self.untargeted => {
+                    self.untargeted = true;
+                    self.status(
+                        "records carry no target, so --target hides nothing; \
+                         use the verbose style or --cobs",
+                    );
+                }
+                None => {}
+            }
+        }
+        match line.level {
+            Some(level) if self.opts.color => println!("{}{}\x1b[0m", level.color(), line.text),
+            _ => println!("{}", line.text),
+        }
+        self.tee(&line.text);
+    }
+
+    fn status(&mut self, msg: &str) {
+        eprintln!("-- {} --", msg);
+        self.tee(&format!("-- {} --", msg));
+    }
+
+    fn tee(&mut self, text: &str) {
+        if let Some(log) = &mut self.log {
+            if let Err(e) = log.write_line(text) {
+                eprintln!("failed to write log file: {}", e);
+            }
+        }
+    }
+}
+
+/// Log files in a directory, rotated by size.
+struct RotatingLog {
+    dir: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: Option<File>,
+    written: u64,
+}
+
+impl RotatingLog {
+    fn new(dir: PathBuf, max_size: u64, keep: usize) -> Self {
+        RotatingLog {
+            dir,
+            max_size,
+            keep,
+            file: None,
+            written: 0,
+        }
+    }
+
+    fn write_line(&mut self, text: &str) -> io::Result<()> {
+        if self.file.is_none() || self.written >= self.max_size {
+            self.rotate()?;
+        }
+        let line = format!("{} {}\n", Timestamp::now(), text);
+        if let Some(file) = &mut self.file {
+            file.write_all(line.as_bytes())?;
+            self.written += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let stamp = Timestamp::now().file_name();
+        let mut n = 0;
+        let file = loop {
+            let name = match n {
+                0 => format!("monitor-{}.log", stamp),
+                n => format!("monitor-{}-{}.log", stamp, n),
+            };
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(self.dir.join(name))
+            {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                result => break result?,
+            }
+        };
+        self.file = Some(file);
+        self.written = 0;
+        self.prune()
+    }
+
+    /// Remove the oldest log files beyond `keep`.
+    fn prune(&self) -> io::Result<()> {
+        let mut files: Vec<_> = fs::read_dir(&self.dir)?
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with("monitor-") && n.ends_with(".log"))
+            })
+            .collect();
+        // The names sort by the time they were created.
+        files.sort();
+        let excess = files.len().saturating_sub(self.keep.max(1));
+        for path in &files[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// A UTC wall clock time.
+struct Timestamp {
+    year: i64,
+    month: u32,
+    day: u32,
+    secs_of_day: u64,
+    millis: u32,
+}
+
+impl Timestamp {
+    fn now() -> Timestamp {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        Timestamp {
+            year,
+            month,
+            day,
+            secs_of_day: secs % 86400,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+
+    fn file_name(&self) -> String {
+        let s = self.secs_of_day;
+        format!(
+            "{:04}{:02}{:02}-{:02}{:02}{:02}",
+            self.year,
+            self.month,
+            self.day,
+            s / 3600,
+            s / 60 % 60,
+            s % 60
+        )
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = self.secs_of_day;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year,
+            self.month,
+            self.day,
+            s / 3600,
+            s / 60 % 60,
+            s % 60,
+            self.millis
+        )
+    }
+}
+
+/// Convert days since 1970-01-01 to a proleptic Gregorian date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn verbose_header_has_level_and_target() {
+        let line = Line::parse("[12.345 c0 WARN  mcp::io:118] portb = 00000000".into());
+        assert_eq!(line.level, Some(Level::Warn));
+        assert_eq!(line.target.as_deref(), Some("mcp::io"));
+    }
+
+    #[test]
+    fn compact_header_has_no_target() {
+        let line = Line::parse("[12.345 c1 INFO ] portb = 00000000".into());
+        assert_eq!(line.level, Some(Level::Info));
+        assert_eq!(line.target, None);
+    }
+
+    #[test]
+    fn console_output_has_no_header() {
+        let line = Line::parse("filter: main=debug".into());
+        assert_eq!(line.level, None);
+        assert_eq!(line.target, None);
+    }
+
+    #[test]
+    fn options_are_parsed() {
+        let opts = Options::parse(&args(
+            "/dev/ttyACM0 --level debug --target mcp --keep 3 --cobs --no-color",
+        ))
+        .unwrap();
+        assert_eq!(opts.port, "/dev/ttyACM0");
+        assert_eq!(opts.level, Some(Level::Debug));
+        assert_eq!(opts.target.as_deref(), Some("mcp"));
+        assert_eq!(opts.keep, 3);
+        assert!(opts.cobs);
+        assert!(!opts.color);
+    }
+
+    #[test]
+    fn bad_options_are_rejected() {
+        assert_eq!(Options::parse(&args("--cobs")).unwrap_err(), "missing port");
+        assert_eq!(
+            Options::parse(&args("port --level loud")).unwrap_err(),
+            "bad level loud"
+        );
+        assert_eq!(
+            Options::parse(&args("port --keep")).unwrap_err(),
+            "--keep needs a value"
+        );
+        assert_eq!(
+            Options::parse(&args("port --baud 9600")).unwrap_err(),
+            "unknown option --baud"
+        );
+        assert_eq!(
+            Options::parse(&args("port other")).unwrap_err(),
+            "unexpected argument other"
+        );
+    }
+
+    #[test]
+    fn days_are_converted_to_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19783), (2024, 3, 1));
+    }
+}
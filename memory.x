@@ -1,6 +1,10 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 256K
+    /* Log kept across power loss, see                */
+    /* src/flashlog.rs. It must start and end on a    */
+    /* 4K erase sector boundary.                      */
+    FLASHLOG : ORIGIN = 0x101C0000, LENGTH = 256K
 
     /* Pick one of the two options for RAM layout     */
 
@@ -8,10 +12,29 @@ MEMORY {
     /* Reasonable, unless you are doing something     */
     /* really particular with DMA or other concurrent */
     /* access that would benefit from striping        */
-    RAM   : ORIGIN = 0x20000000, LENGTH = 264K
+    /* The SCRATCH banks are taken by CRASHLOG below. */
+    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 
     /* OPTION B: Keep the unstriped sections separate */
     /* RAM: ORIGIN = 0x20000000, LENGTH = 256K        */
     /* SCRATCH_A: ORIGIN = 0x20040000, LENGTH = 4K    */
     /* SCRATCH_B: ORIGIN = 0x20041000, LENGTH = 4K    */
+
+    /* Log history and crash record kept across soft  */
+    /* and watchdog resets, see src/crashlog.rs. The  */
+    /* top 2K is left to the bootrom, which puts its  */
+    /* stack and boot2 there on every reset.          */
+    CRASHLOG : ORIGIN = 0x20040000, LENGTH = 6K
 }
+
+/* The partition used by flashlog::partition(). */
+__flashlog_start = ORIGIN(FLASHLOG);
+__flashlog_end = ORIGIN(FLASHLOG) + LENGTH(FLASHLOG);
+
+SECTIONS {
+    /* Neither loaded nor zeroed at startup, so the contents survive a reset. */
+    .crashlog (NOLOAD) : ALIGN(4)
+    {
+        KEEP(*(.crashlog .crashlog.*));
+    } > CRASHLOG
+} INSERT AFTER .bss;
@@ -0,0 +1,97 @@
+//! USB identity of the logger device.
+
+#[cfg(target_os = "none")]
+use embassy_rp::flash::{Flash, Mode};
+#[cfg(target_os = "none")]
+use embassy_rp::peripherals::FLASH;
+
+/// Where the USB serial number string comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SerialNumber<'a> {
+    /// The 64-bit unique ID of the QSPI flash chip, as read by
+    /// `read_unique_id`, written as 16 hex digits.
+    UniqueId([u8; 8]),
+    /// A fixed string.
+    Custom(&'a str),
+    /// No serial number descriptor.
+    #[default]
+    Omit,
+}
+
+/// The USB device configuration used by `UsbLogger::run`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggerConfig<'a> {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<&'a str>,
+    pub product: Option<&'a str>,
+    pub serial_number: SerialNumber<'a>,
+    /// Maximum current drawn from the bus, in mA.
+    pub max_power: u16,
+}
+
+impl Default for LoggerConfig<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoggerConfig<'_> {
+    /// The default configuration, without a serial number.
+    ///
+    /// The RP2040 has no unique ID of its own. The only one on the board is
+    /// that of the flash chip, which is read through the flash peripheral, so
+    /// it is left to `from_flash`, which should normally be used instead.
+    pub const fn new() -> Self {
+        Self {
+            vendor_id: 0xc0de,
+            product_id: 0xcafe,
+            manufacturer: Some("Embassy"),
+            product: Some("USB-serial logger"),
+            serial_number: SerialNumber::Omit,
+            max_power: 100,
+        }
+    }
+
+    /// The default configuration, with the flash unique ID as the serial
+    /// number.
+    ///
+    /// Call it before handing the flash to anything that erases or programs
+    /// it, see `read_unique_id`.
+    #[cfg(target_os = "none")]
+    pub fn from_flash<M: Mode, const FLASH_SIZE: usize>(
+        flash: &mut Flash<'_, FLASH, M, FLASH_SIZE>,
+    ) -> Self {
+        Self {
+            serial_number: read_unique_id(flash),
+            ..Self::new()
+        }
+    }
+}
+
+/// Read the flash unique ID to use as the serial number, or `Omit` if it
+/// cannot be read.
+///
+/// Call it before handing the flash to anything that erases or programs it,
+/// such as a `FlashSink`, as reading the ID takes the flash out of XIP mode.
+#[cfg(target_os = "none")]
+pub fn read_unique_id<M: Mode, const FLASH_SIZE: usize>(
+    flash: &mut Flash<'_, FLASH, M, FLASH_SIZE>,
+) -> SerialNumber<'static> {
+    let mut uid = [0u8; 8];
+    match flash.blocking_unique_id(&mut uid) {
+        Ok(()) => SerialNumber::UniqueId(uid),
+        Err(_) => SerialNumber::Omit,
+    }
+}
+
+/// Write `uid` into `buf` as upper case hex digits.
+pub(crate) fn format_unique_id<'a>(uid: &[u8; 8], buf: &'a mut [u8; 16]) -> Option<&'a str> {
+    for (i, b) in uid.iter().enumerate() {
+        buf[2 * i] = HEX[(b >> 4) as usize];
+        buf[2 * i + 1] = HEX[(b & 0xf) as usize];
+    }
+    core::str::from_utf8(buf).ok()
+}
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
@@ -0,0 +1,327 @@
+//! A line-based command console running on the logger's CDC receive path.
+//!
+//! Bytes received from the host are echoed back into the log stream, edited
+//! in a small line buffer and, once a line is terminated, dispatched to a
+//! command registered in [`COMMANDS`].
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::CS;
+
+/// The maximum number of commands that can be registered.
+pub const MAX_COMMANDS: usize = 16;
+
+/// The maximum length of a single console input line.
+pub const LINE_LEN: usize = 128;
+
+const PROMPT: &str = "> ";
+
+/// A command handler. Receives everything after the command name, trimmed.
+pub type Handler = fn(args: &str, out: &mut dyn Write);
+
+/// A named console command.
+#[derive(Clone, Copy)]
+pub struct Command {
+    /// The name the command is invoked with.
+    pub name: &'static str,
+    /// A one-line description shown by `help`.
+    pub help: &'static str,
+    /// The function called when the command is invoked.
+    pub handler: Handler,
+}
+
+/// Errors returned when registering a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// All `MAX_COMMANDS` slots are in use.
+    Full,
+    /// A command with the same name is already registered.
+    Duplicate,
+}
+
+/// A fixed-size table of console commands.
+pub struct CommandRegistry {
+    commands: Mutex<CS, RefCell<[Option<Command>; MAX_COMMANDS]>>,
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandRegistry {
+    /// Create an empty registry.
+    pub const fn new() -> Self {
+        Self {
+            commands: Mutex::new(RefCell::new([None; MAX_COMMANDS])),
+        }
+    }
+
+    /// Register a command with the given name, help text and handler.
+    pub fn register(
+        &self,
+        name: &'static str,
+        help: &'static str,
+        handler: Handler,
+    ) -> Result<(), RegisterError> {
+        self.commands.lock(|commands| {
+            let mut commands = commands.borrow_mut();
+            if commands.iter().flatten().any(|c| c.name == name) {
+                return Err(RegisterError::Duplicate);
+            }
+            let slot = commands
+                .iter_mut()
+                .find(|c| c.is_none())
+                .ok_or(RegisterError::Full)?;
+            *slot = Some(Command {
+                name,
+                help,
+                handler,
+            });
+            Ok(())
+        })
+    }
+
+    fn find(&self, name: &str) -> Option<Command> {
+        self.commands.lock(|commands| {
+            commands
+                .borrow()
+                .iter()
+                .flatten()
+                .find(|c| c.name == name)
+                .copied()
+        })
+    }
+
+    fn help(&self, out: &mut dyn Write) {
+        let _ = write!(out, "help - list available commands\r\n");
+        self.commands.lock(|commands| {
+            for command in commands.borrow().iter().flatten() {
+                let _ = write!(out, "{} - {}\r\n", command.name, command.help);
+            }
+        });
+    }
+
+    /// Run a single command line, writing its output to `out`.
+    ///
+    /// The handler is called without holding the registry lock, so it may itself
+    /// register further commands.
+    pub fn dispatch(&self, line: &str, out: &mut dyn Write) {
+        let (name, args) = split_command(line);
+        if name.is_empty() {
+            return;
+        }
+        if name == "help" {
+            self.help(out);
+            return;
+        }
+        match self.find(name) {
+            Some(command) => (command.handler)(args, out),
+            None => {
+                let _ = write!(out, "unknown command: {}\r\n", name);
+            }
+        }
+    }
+}
+
+/// The global command registry used by the USB logger console.
+pub static COMMANDS: CommandRegistry = CommandRegistry::new();
+
+/// Register a command on the global console.
+///
+/// # Usage
+///
+/// ```
+/// rp2040_project_template::console::register("ping", "reply with pong", |_, out| {
+///     let _ = write!(out, "pong\r\n");
+/// })
+/// .unwrap();
+/// ```
+pub fn register(
+    name: &'static str,
+    help: &'static str,
+    handler: Handler,
+) -> Result<(), RegisterError> {
+    COMMANDS.register(name, help, handler)
+}
+
+/// Split a line into the command name and its trimmed arguments.
+pub(crate) fn split_command(line: &str) -> (&str, &str) {
+    let line = line.trim();
+    match line.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (line, ""),
+    }
+}
+
+/// The line editor state of a console session.
+pub(crate) struct Console {
+    line: [u8; LINE_LEN],
+    len: usize,
+    last_cr: bool,
+}
+
+impl Console {
+    pub(crate) const fn new() -> Self {
+        Self {
+            line: [0; LINE_LEN],
+            len: 0,
+            last_cr: false,
+        }
+    }
+
+    /// Feed received bytes through the line editor, echoing to `out` and
+    /// calling `exec` for every completed line.
+    pub(crate) fn process(
+        &mut self,
+        bytes: &[u8],
+        out: &mut dyn Write,
+        exec: &mut dyn FnMut(&str, &mut dyn Write),
+    ) {
+        for &b in bytes {
+            let last_cr = core::mem::replace(&mut self.last_cr, b == b'\r');
+            match b {
+                // Treat CR LF as a single line ending.
+                b'\n' if last_cr => {}
+                b'\r' | b'\n' => {
+                    let _ = out.write_str("\r\n");
+                    // Only printable ASCII is ever stored in the line buffer.
+                    let line = core::str::from_utf8(&self.line[..self.len]).unwrap_or("");
+                    exec(line, out);
+                    self.len = 0;
+                    let _ = out.write_str(PROMPT);
+                }
+                // Backspace and delete.
+                0x08 | 0x7f if self.len > 0 => {
+                    self.len -= 1;
+                    let _ = out.write_str("\x08 \x08");
+                }
+                // Ctrl-C discards the current line.
+                0x03 => {
+                    self.len = 0;
+                    let _ = out.write_str("^C\r\n");
+                    let _ = out.write_str(PROMPT);
+                }
+                0x20..=0x7e => {
+                    if self.len < LINE_LEN {
+                        self.line[self.len] = b;
+                        self.len += 1;
+                        let _ = out.write_char(b as char);
+                    } else {
+                        let _ = out.write_char('\x07');
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `input` through `console`, returning the echo and the executed lines.
+    fn feed(console: &mut Console, input: &[u8]) -> (String, Vec<String>) {
+        let mut echo = String::new();
+        let mut lines = Vec::new();
+        console.process(input, &mut echo, &mut |line, _| {
+            lines.push(line.to_string())
+        });
+        (echo, lines)
+    }
+
+    fn noop(_: &str, _: &mut dyn Write) {}
+
+    #[test]
+    fn backspace_and_delete_remove_the_last_character() {
+        let mut console = Console::new();
+        let (echo, lines) = feed(&mut console, b"ab\x08c\x7fd\r");
+        assert_eq!(lines, ["ad"]);
+        assert_eq!(echo, "ab\x08 \x08c\x08 \x08d\r\n> ");
+    }
+
+    #[test]
+    fn backspace_on_an_empty_line_is_ignored() {
+        let mut console = Console::new();
+        let (echo, lines) = feed(&mut console, b"\x08\x7fa\r");
+        assert_eq!(lines, ["a"]);
+        assert_eq!(echo, "a\r\n> ");
+    }
+
+    #[test]
+    fn cr_lf_and_crlf_each_end_one_line() {
+        let mut console = Console::new();
+        let (_, lines) = feed(&mut console, b"one\rtwo\nthree\r\nfour\n\n");
+        assert_eq!(lines, ["one", "two", "three", "four", ""]);
+    }
+
+    #[test]
+    fn crlf_split_across_reads_ends_one_line() {
+        let mut console = Console::new();
+        let (_, lines) = feed(&mut console, b"one\r");
+        assert_eq!(lines, ["one"]);
+        let (echo, lines) = feed(&mut console, b"\ntwo\r");
+        assert_eq!(lines, ["two"]);
+        assert_eq!(echo, "two\r\n> ");
+    }
+
+    #[test]
+    fn ctrl_c_discards_the_line() {
+        let mut console = Console::new();
+        let (echo, lines) = feed(&mut console, b"reboot\x03ok\r");
+        assert_eq!(lines, ["ok"]);
+        assert_eq!(echo, "reboot^C\r\n> ok\r\n> ");
+    }
+
+    #[test]
+    fn overflowing_the_line_rings_the_bell() {
+        let mut console = Console::new();
+        let input = [b'x'; LINE_LEN + 2];
+        let (echo, lines) = feed(&mut console, &input);
+        assert!(lines.is_empty());
+        assert_eq!(echo.len(), LINE_LEN + 2);
+        assert!(echo.ends_with("x\x07\x07"));
+
+        let (_, lines) = feed(&mut console, b"\r");
+        assert_eq!(lines, ["x".repeat(LINE_LEN)]);
+    }
+
+    #[test]
+    fn registry_is_full_at_max_commands() {
+        const NAMES: [&str; MAX_COMMANDS + 1] = [
+            "c0", "c1", "c2", "c3", "c4", "c5", "c6", "c7", "c8", "c9", "c10", "c11", "c12", "c13",
+            "c14", "c15", "c16",
+        ];
+        let registry = CommandRegistry::new();
+        for name in &NAMES[..MAX_COMMANDS] {
+            assert_eq!(registry.register(name, "", noop), Ok(()));
+        }
+        assert_eq!(
+            registry.register(NAMES[MAX_COMMANDS], "", noop),
+            Err(RegisterError::Full)
+        );
+        assert_eq!(
+            registry.register("c0", "", noop),
+            Err(RegisterError::Duplicate)
+        );
+    }
+
+    #[test]
+    fn dispatch_passes_trimmed_arguments() {
+        let registry = CommandRegistry::new();
+        registry
+            .register("echo", "", |args, out| {
+                let _ = write!(out, "[{}]", args);
+            })
+            .unwrap();
+        let mut out = String::new();
+        registry.dispatch("  echo   a b  ", &mut out);
+        registry.dispatch("nope", &mut out);
+        assert_eq!(out, "[a b]unknown command: nope\r\n");
+    }
+}
@@ -0,0 +1,365 @@
+//! Log history and crash record kept across resets in uninitialized RAM.
+//!
+//! The `.crashlog` section is placed in the `CRASHLOG` region of `memory.x`,
+//! which the startup code neither loads nor zeroes. It holds two boot slots.
+//! Every boot writes its log output and any crash message into one of them and
+//! leaves the other, written by the previous boot, untouched so the logger can
+//! replay it once a host connects.
+//!
+//! After a power cycle the RAM contents are random, so the header carries a
+//! magic value and each slot is bounds-checked before it is trusted.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::ptr::{addr_of, addr_of_mut, read_volatile};
+
+use portable_atomic::{AtomicBool, Ordering};
+
+/// The number of bytes of log output kept per boot.
+pub const HISTORY_LEN: usize = 2048;
+
+/// The maximum length of a crash message.
+pub const CRASH_LEN: usize = 512;
+
+const MAGIC: u32 = 0x10c5_a7ed;
+
+#[repr(C)]
+struct BootSlot {
+    /// The number of valid bytes in `history`, at most `HISTORY_LEN`.
+    len: u32,
+    /// The next write position in `history`.
+    head: u32,
+    crash_len: u32,
+    history: [u8; HISTORY_LEN],
+    crash: [u8; CRASH_LEN],
+}
+
+impl BootSlot {
+    /// Check the counters of a slot left by the previous boot.
+    ///
+    /// # Safety
+    ///
+    /// `slot` must point into `PERSIST`, with no reference to it alive.
+    unsafe fn is_valid(slot: *const Self) -> bool {
+        read_volatile(addr_of!((*slot).len)) as usize <= HISTORY_LEN
+            && (read_volatile(addr_of!((*slot).head)) as usize) < HISTORY_LEN
+            && read_volatile(addr_of!((*slot).crash_len)) as usize <= CRASH_LEN
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        // Only the tail of a record longer than the history can survive.
+        let bytes = &bytes[bytes.len().saturating_sub(HISTORY_LEN)..];
+        let head = self.head as usize;
+        let first = bytes.len().min(HISTORY_LEN - head);
+        self.history[head..head + first].copy_from_slice(&bytes[..first]);
+        self.history[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+        self.head = ((head + bytes.len()) % HISTORY_LEN) as u32;
+        self.len = (self.len as usize + bytes.len()).min(HISTORY_LEN) as u32;
+    }
+
+    /// The history in order, as up to two slices around the ring wraparound.
+    fn history(&self) -> [&[u8]; 2] {
+        let (len, head) = (self.len as usize, self.head as usize);
+        if len < HISTORY_LEN {
+            [&self.history[..len], &[]]
+        } else {
+            [&self.history[head..], &self.history[..head]]
+        }
+    }
+
+    fn crash(&self) -> Option<&[u8]> {
+        (self.crash_len > 0).then(|| &self.crash[..self.crash_len as usize])
+    }
+}
+
+#[repr(C)]
+struct Persist {
+    magic: u32,
+    /// The slot written by the current boot.
+    current: u32,
+    /// `!(magic ^ current)`, to reject a header that only matches by chance.
+    check: u32,
+    /// The number of crashes since the last power-on reset.
+    crash_count: u32,
+    slots: [BootSlot; 2],
+}
+
+struct Persistent(UnsafeCell<MaybeUninit<Persist>>);
+
+// Safety: the current slot is only accessed through `with_current`, inside a
+// critical section, and the previous slot is only ever read.
+unsafe impl Sync for Persistent {}
+
+#[link_section = ".crashlog"]
+static PERSIST: Persistent = Persistent(UnsafeCell::new(MaybeUninit::uninit()));
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static PREVIOUS_VALID: AtomicBool = AtomicBool::new(false);
+static PREVIOUS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Run `f` on the current boot's slot and the crash count, switching slots
+/// first if this is the first access since reset.
+fn with_current<R>(f: impl FnOnce(&mut BootSlot, &mut u32) -> R) -> R {
+    critical_section::with(|_| {
+        let persist = PERSIST.0.get().cast::<Persist>();
+        // Safety: the critical section gives exclusive access. Until `init`
+        // has run, nothing but raw pointers touch the region. After it, the
+        // header and the current slot's counters have been written by this
+        // boot, and the current slot is only read within what this boot wrote.
+        unsafe {
+            if !INITIALIZED.load(Ordering::Relaxed) {
+                INITIALIZED.store(true, Ordering::Relaxed);
+                PREVIOUS_VALID.store(init(persist), Ordering::Relaxed);
+            }
+            let current = (*persist).current as usize;
+            f(&mut (*persist).slots[current], &mut (*persist).crash_count)
+        }
+    })
+}
+
+/// Switch to the slot not written by the previous boot. Returns whether the
+/// previous boot's slot is valid.
+///
+/// # Safety
+///
+/// `persist` must point at `PERSIST`, with exclusive access and no reference
+/// to it alive.
+unsafe fn init(persist: *mut Persist) -> bool {
+    // The region is not initialized by the startup code, so it holds whatever
+    // the previous boot left, or noise after a power cycle. It is read through
+    // raw pointers with volatile reads, which take the values as they are in
+    // RAM without the compiler assuming anything about them, and each one is
+    // range-checked before it is used.
+    let magic = read_volatile(addr_of!((*persist).magic));
+    let check = read_volatile(addr_of!((*persist).check));
+    let current = read_volatile(addr_of!((*persist).current));
+    let header_valid = magic == MAGIC && check == !(MAGIC ^ current) && current < 2;
+    let previous = current as usize & 1;
+    let previous_valid = header_valid && BootSlot::is_valid(addr_of!((*persist).slots[previous]));
+    let current = if previous_valid {
+        previous ^ 1
+    } else {
+        clear(addr_of_mut!((*persist).slots[1]));
+        addr_of_mut!((*persist).crash_count).write(0);
+        0
+    };
+    clear(addr_of_mut!((*persist).slots[current]));
+    addr_of_mut!((*persist).current).write(current as u32);
+    addr_of_mut!((*persist).magic).write(MAGIC);
+    addr_of_mut!((*persist).check).write(!(MAGIC ^ current as u32));
+    previous_valid
+}
+
+/// Reset the counters of a slot.
+///
+/// # Safety
+///
+/// `slot` must point into `PERSIST`, with no reference to it alive.
+unsafe fn clear(slot: *mut BootSlot) {
+    addr_of_mut!((*slot).len).write(0);
+    addr_of_mut!((*slot).head).write(0);
+    addr_of_mut!((*slot).crash_len).write(0);
+}
+
+/// Append log output to this boot's history.
+pub fn record(bytes: &[u8]) {
+    with_current(|slot, _| slot.push(bytes));
+}
+
+/// Store a crash message for the next boot to report and count the crash. Only
+/// the first crash of a boot is kept, truncated to `CRASH_LEN` bytes.
+pub fn record_crash(args: fmt::Arguments) {
+    with_current(|slot, crash_count| {
+        if slot.crash_len != 0 {
+            return;
+        }
+        *crash_count = crash_count.wrapping_add(1);
+        let mut w = CrashWriter {
+            buf: &mut slot.crash,
+            len: 0,
+        };
+        let _ = w.write_fmt(args);
+        slot.crash_len = w.len as u32;
+    });
+}
+
+/// What the previous boot left behind.
+pub struct PreviousBoot {
+    /// The log history in order, as up to two slices around the ring wraparound.
+    pub history: [&'static [u8]; 2],
+    /// Whether older output was overwritten, so the first line is incomplete.
+    pub wrapped: bool,
+    /// The crash message, if the previous boot crashed.
+    pub crash: Option<&'static [u8]>,
+}
+
+/// The number of crashes recorded since the last power-on reset, including
+/// one recorded during this boot.
+pub fn crash_count() -> u32 {
+    with_current(|_, crash_count| *crash_count)
+}
+
+/// The crash message of the previous boot, if it crashed.
+pub fn previous_crash() -> Option<&'static str> {
+    core::str::from_utf8(previous_slot()?.crash()?).ok()
+}
+
+fn previous_slot() -> Option<&'static BootSlot> {
+    // Make sure the slots have been switched for this boot.
+    with_current(|_, _| ());
+    if !PREVIOUS_VALID.load(Ordering::Relaxed) {
+        return None;
+    }
+    // Safety: the header has been initialized, and the previous boot's slot is
+    // never written again during this boot.
+    Some(unsafe {
+        let persist = PERSIST.0.get().cast::<Persist>();
+        &(*persist).slots[(*persist).current as usize ^ 1]
+    })
+}
+
+/// Returns the previous boot's log, once per boot.
+pub fn take_previous_boot() -> Option<PreviousBoot> {
+    let slot = previous_slot()?;
+    if PREVIOUS_TAKEN.swap(true, Ordering::Relaxed) {
+        return None;
+    }
+    Some(PreviousBoot {
+        history: slot.history(),
+        wrapped: slot.len as usize == HISTORY_LEN,
+        crash: slot.crash(),
+    })
+}
+
+struct CrashWriter<'a> {
+    buf: &'a mut [u8; CRASH_LEN],
+    len: usize,
+}
+
+impl Write for CrashWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(CRASH_LEN - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Persist` region as left in RAM, filled with `noise`.
+    fn region(noise: u8) -> Box<MaybeUninit<Persist>> {
+        let mut persist = Box::new(MaybeUninit::<Persist>::uninit());
+        // Safety: any byte pattern can be written to uninitialized memory.
+        unsafe { persist.as_mut_ptr().write_bytes(noise, 1) };
+        persist
+    }
+
+    /// Simulate a reset: switch slots as `with_current` does on first access.
+    fn boot(persist: &mut MaybeUninit<Persist>) -> bool {
+        // Safety: the box gives exclusive access and no reference is alive.
+        unsafe { init(persist.as_mut_ptr()) }
+    }
+
+    fn persist(persist: &mut MaybeUninit<Persist>) -> &mut Persist {
+        // Safety: `boot` has written the header and reset the slots' counters,
+        // and every byte was initialized by `region`.
+        unsafe { persist.assume_init_mut() }
+    }
+
+    fn current(persist: &mut Persist) -> &mut BootSlot {
+        &mut persist.slots[persist.current as usize]
+    }
+
+    fn previous(persist: &Persist) -> &BootSlot {
+        &persist.slots[persist.current as usize ^ 1]
+    }
+
+    #[test]
+    fn power_on_noise_has_no_previous_slot() {
+        for noise in [0x00, 0xa5, 0xff] {
+            let mut region = region(noise);
+            assert!(!boot(&mut region));
+            let persist = persist(&mut region);
+            assert_eq!(persist.current, 0);
+            assert_eq!(persist.crash_count, 0);
+            assert_eq!(previous(persist).history(), [&[][..], &[]]);
+            assert_eq!(previous(persist).crash(), None);
+        }
+    }
+
+    #[test]
+    fn previous_boot_is_kept_in_the_other_slot() {
+        let mut region = region(0xa5);
+        boot(&mut region);
+        let first = persist(&mut region);
+        current(first).push(b"first boot");
+        first.crash_count = 1;
+        let slot = current(first);
+        slot.crash[..5].copy_from_slice(b"panic");
+        slot.crash_len = 5;
+
+        assert!(boot(&mut region));
+        let second = persist(&mut region);
+        assert_eq!(second.current, 1);
+        assert_eq!(second.crash_count, 1);
+        assert_eq!(previous(second).history(), [&b"first boot"[..], &[]]);
+        assert_eq!(previous(second).crash(), Some(&b"panic"[..]));
+        assert_eq!(current(second).history(), [&[][..], &[]]);
+        current(second).push(b"second boot");
+
+        assert!(boot(&mut region));
+        let third = persist(&mut region);
+        assert_eq!(third.current, 0);
+        assert_eq!(previous(third).history(), [&b"second boot"[..], &[]]);
+        assert_eq!(previous(third).crash(), None);
+    }
+
+    #[test]
+    fn wrapped_history_is_returned_in_order() {
+        let mut region = region(0);
+        boot(&mut region);
+        let persist = persist(&mut region);
+        let output: Vec<u8> = (0..HISTORY_LEN + 100).map(|i| i as u8).collect();
+        current(persist).push(&output[..HISTORY_LEN - 10]);
+        current(persist).push(&output[HISTORY_LEN - 10..]);
+
+        let [first, second] = current(persist).history();
+        assert_eq!([first, second].concat(), &output[100..]);
+    }
+
+    #[test]
+    fn corrupted_slot_is_discarded() {
+        let mut region = region(0xa5);
+        boot(&mut region);
+        let first = persist(&mut region);
+        current(first).push(b"first boot");
+        first.crash_count = 3;
+        current(first).head = HISTORY_LEN as u32;
+
+        assert!(!boot(&mut region));
+        let second = persist(&mut region);
+        assert_eq!(second.current, 0);
+        assert_eq!(second.crash_count, 0);
+        assert_eq!(previous(second).history(), [&[][..], &[]]);
+    }
+
+    #[test]
+    fn corrupted_header_is_discarded() {
+        let mut region = region(0xa5);
+        boot(&mut region);
+        current(persist(&mut region)).push(b"first boot");
+        persist(&mut region).check ^= 1;
+
+        assert!(!boot(&mut region));
+        let second = persist(&mut region);
+        assert_eq!(second.current, 0);
+        assert_eq!(previous(second).history(), [&[][..], &[]]);
+    }
+}
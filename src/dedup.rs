@@ -0,0 +1,105 @@
+//! Collapsing of repeated identical records.
+
+use core::fmt::{self, Write};
+
+use embassy_time::{Duration, Instant};
+use log::{Level, Record};
+
+/// What to do with a record after comparing it to the previous one.
+pub(crate) enum Check {
+    /// Log the record, after reporting how often the previous record was
+    /// repeated, if at all.
+    Log(Option<Repeated>),
+    /// Drop the record, it repeats the previous one.
+    Suppress,
+    /// Drop the record and report the repeats so far, because the first of
+    /// them is older than the interval.
+    Report(Repeated),
+}
+
+/// Repeats of a record that were not logged.
+#[derive(Clone, Copy)]
+pub(crate) struct Repeated {
+    pub(crate) level: Level,
+    pub(crate) count: u32,
+}
+
+/// The previous record and how often it has been repeated since.
+pub(crate) struct Repeats {
+    /// The hash of the previous record.
+    key: Option<u32>,
+    level: Level,
+    count: u32,
+    /// When the first unreported repeat was seen.
+    since: Instant,
+}
+
+impl Repeats {
+    pub(crate) const fn new() -> Self {
+        Self {
+            key: None,
+            level: Level::Info,
+            count: 0,
+            since: Instant::MIN,
+        }
+    }
+
+    /// Compare a record, given by its `hash` and level, to the previous one.
+    pub(crate) fn check(&mut self, key: u32, level: Level, interval: Duration) -> Check {
+        let now = Instant::now();
+        if self.key != Some(key) {
+            let repeated = self.take();
+            self.key = Some(key);
+            self.level = level;
+            return Check::Log(repeated);
+        }
+        if self.count == 0 {
+            self.since = now;
+        }
+        self.count += 1;
+        if now.saturating_duration_since(self.since) < interval {
+            return Check::Suppress;
+        }
+        match self.take() {
+            Some(repeated) => Check::Report(repeated),
+            None => Check::Suppress,
+        }
+    }
+
+    fn take(&mut self) -> Option<Repeated> {
+        let count = core::mem::take(&mut self.count);
+        (count > 0).then_some(Repeated {
+            level: self.level,
+            count,
+        })
+    }
+}
+
+/// Hash the level, target and message of a record, which are what make two
+/// records identical.
+pub(crate) fn hash(record: &Record) -> u32 {
+    let mut hasher = Fnv(0x811c_9dc5);
+    hasher.write_bytes(&[record.level() as u8]);
+    hasher.write_bytes(record.target().as_bytes());
+    hasher.write_bytes(&[0]);
+    let _ = write!(hasher, "{}", record.args());
+    hasher.0
+}
+
+/// The 32-bit FNV-1a hash.
+struct Fnv(u32);
+
+impl Fnv {
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u32).wrapping_mul(0x0100_0193);
+        }
+    }
+}
+
+impl Write for Fnv {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
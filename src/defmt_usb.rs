@@ -0,0 +1,145 @@
+//! A defmt global logger that sends frames through a `UsbLogger`.
+//!
+//! Enabled by the `defmt-usb` feature, which replaces `defmt-rtt`. Every defmt
+//! frame is rzCOBS encoded, terminated by a zero byte and staged into the
+//! logger set with `set_target`, which must use `Encoding::Defmt` so that its
+//! own output is sent as defmt frames too. Until a target is set, frames are
+//! discarded.
+//!
+//! On the host, decode the port with `decode-defmt <elf> <port>` from the
+//! `debug` crate, using the ELF file the firmware was built from.
+
+use core::cell::{Cell, UnsafeCell};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::{UsbLogger, CS, MAX_RECORD_LEN};
+
+defmt::timestamp!("{=u64:us}", Instant::now().as_micros());
+
+#[defmt::global_logger]
+struct DefmtUsb;
+
+/// A logger that defmt frames can be staged into.
+trait Target: Sync {
+    /// Stage a complete frame, returning false if it does not fit.
+    fn stage_frame(&self, frame: &[u8]) -> bool;
+
+    /// Account for a frame of `len` bytes as dropped.
+    fn drop_frame(&self, len: usize);
+}
+
+impl<const N: usize> Target for UsbLogger<N> {
+    fn stage_frame(&self, frame: &[u8]) -> bool {
+        self.stage(frame)
+    }
+
+    fn drop_frame(&self, len: usize) {
+        self.drops.add(len, 1);
+    }
+}
+
+static TARGET: Mutex<CS, Cell<Option<&'static dyn Target>>> = Mutex::new(Cell::new(None));
+
+/// Send defmt frames through `logger` from now on.
+pub fn set_target<const N: usize>(logger: &'static UsbLogger<N>) {
+    TARGET.lock(|target| target.set(Some(logger)));
+}
+
+/// The frame being written and the critical section it is written in, only
+/// accessed while holding the defmt logger.
+struct State {
+    encoder: defmt::Encoder,
+    frame: Frame,
+    restore: critical_section::RestoreState,
+}
+
+/// The encoded bytes of a frame.
+struct Frame {
+    buf: [u8; MAX_RECORD_LEN],
+    len: usize,
+    /// The number of bytes that did not fit into `buf`.
+    overflow: usize,
+}
+
+impl Frame {
+    fn push(&mut self, bytes: &[u8]) {
+        if self.overflow > 0 || self.len + bytes.len() > self.buf.len() {
+            self.overflow += bytes.len();
+            return;
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+}
+
+struct StateCell(UnsafeCell<State>);
+
+// Safety: the state is only accessed by the holder of the defmt logger, which
+// runs in a critical section and is guarded against reentry by `TAKEN`.
+unsafe impl Sync for StateCell {}
+
+static STATE: StateCell = StateCell(UnsafeCell::new(State {
+    encoder: defmt::Encoder::new(),
+    frame: Frame {
+        buf: [0; MAX_RECORD_LEN],
+        len: 0,
+        overflow: 0,
+    },
+    restore: critical_section::RestoreState::invalid(),
+}));
+
+/// Whether the defmt logger is held.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Returns the logger state.
+///
+/// # Safety
+///
+/// Must only be called while holding the defmt logger, and the reference must
+/// not outlive the call into the logger.
+unsafe fn state() -> &'static mut State {
+    &mut *STATE.0.get()
+}
+
+unsafe impl defmt::Logger for DefmtUsb {
+    fn acquire() {
+        // Safety: released in `release`, which defmt calls after every `acquire`.
+        let restore = unsafe { critical_section::acquire() };
+        if TAKEN.swap(true, Ordering::Relaxed) {
+            panic!("defmt logger taken reentrantly");
+        }
+        // Safety: the logger is held from here on.
+        let state = unsafe { state() };
+        state.restore = restore;
+        state.frame.len = 0;
+        state.frame.overflow = 0;
+        let frame = &mut state.frame;
+        state.encoder.start_frame(|b| frame.push(b));
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        let state = state();
+        let frame = &mut state.frame;
+        state.encoder.end_frame(|b| frame.push(b));
+        if let Some(target) = TARGET.lock(Cell::get) {
+            let len = frame.len + frame.overflow;
+            if frame.overflow > 0 || !target.stage_frame(&frame.buf[..frame.len]) {
+                target.drop_frame(len);
+            }
+        }
+        let restore = state.restore;
+        TAKEN.store(false, Ordering::Relaxed);
+        critical_section::release(restore);
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        let state = state();
+        let frame = &mut state.frame;
+        state.encoder.write(bytes, |b| frame.push(b));
+    }
+}
@@ -0,0 +1,940 @@
+//! A composite USB device with the logger as one of its functions.
+//!
+//! `UsbLogger::run` builds a device with nothing but the logger's serial port.
+//! `DeviceBuilder` builds the same device and lets the application add its own
+//! classes next to the logger on the same bus, such as a second serial port,
+//! HID or a vendor interface:
+//!
+//! ```
+//! static STATE: StaticCell<DeviceState> = StaticCell::new();
+//! static PORT: StaticCell<State> = StaticCell::new();
+//!
+//! let config = LoggerConfig::from_flash(&mut flash);
+//! let mut builder = DeviceBuilder::new(driver, config, STATE.init(DeviceState::new()), &LOGGER);
+//! let port = builder.add_serial_port(PORT.init(State::new())).unwrap();
+//! let mut device = builder.build();
+//! join(device.run(), bridge(port)).await;
+//! ```
+//!
+//! Every class added counts against the descriptor and control buffers in
+//! `DeviceState` and against the interfaces and handlers `embassy_usb` has room
+//! for, so one that does not fit is reported as an error when it is added
+//! instead of as a panic inside `embassy_usb`. `embassy_usb` does not report
+//! how much a class writes while it is added, so the room it takes is measured
+//! beforehand with `ClassSpace::measure`, by building it into a device of its
+//! own.
+
+use core::cell::{Cell, UnsafeCell};
+use core::fmt;
+use core::mem::MaybeUninit;
+
+use embassy_futures::join::join;
+use embassy_usb::class::cdc_acm::{CdcAcmClass, ControlChanged, Receiver, Sender, State};
+use embassy_usb::driver::{
+    self, Direction, Driver, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo,
+    EndpointType, Event, Unsupported,
+};
+use embassy_usb::{Builder, Config, UsbDevice};
+
+use crate::config::{LoggerConfig, SerialNumber};
+use crate::link::LinkHandler;
+use crate::{UsbLogger, MAX_PACKET_SIZE};
+
+/// The number of interfaces `embassy_usb` has room for, its default
+/// `MAX_INTERFACE_COUNT`.
+const MAX_INTERFACES: usize = 4;
+
+/// The number of handlers `embassy_usb` has room for, its default
+/// `MAX_HANDLER_COUNT`.
+const MAX_HANDLERS: usize = 4;
+
+/// The handler for the link state, registered next to the logger's port.
+const LINK_HANDLERS: usize = 1;
+
+/// The size of the BOS descriptor buffer.
+const BOS_LEN: usize = 64;
+
+/// The size of the Microsoft OS descriptor buffer.
+const MSOS_LEN: usize = 256;
+
+/// The size of the configuration descriptor buffer a class is measured in.
+const SCRATCH_CONFIG_LEN: usize = 512;
+
+/// The room for the state of a class being measured.
+const SCRATCH_STATE_LEN: usize = 512;
+
+/// The descriptor type of an interface.
+const INTERFACE_DESCRIPTOR: u8 = 4;
+
+/// The longest control transfer of a CDC ACM serial port, its line coding.
+const CDC_ACM_CONTROL_LEN: usize = 7;
+
+/// The room a class takes in the buffers of `DeviceState`.
+///
+/// The descriptors and interfaces are measured by `ClassSpace::measure`. The
+/// control transfers and handlers of a class cannot be seen from the outside,
+/// so they are stated with `with_control` and `with_handlers`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClassSpace {
+    /// The length of its configuration descriptors, including the interface
+    /// association descriptor written for it.
+    config: usize,
+    /// The length of the BOS capabilities it adds.
+    bos: usize,
+    /// The length of the Microsoft OS 2.0 descriptors it adds, including the
+    /// headers they cause to be written.
+    msos: usize,
+    /// The longest control transfer it handles, such as a HID report
+    /// descriptor.
+    control: usize,
+    /// The number of interfaces it adds.
+    interfaces: usize,
+    /// The number of handlers it registers with `Builder::handler`.
+    handlers: usize,
+}
+
+impl ClassSpace {
+    /// Measure the room a class takes by building it with `f` into a device
+    /// of its own, on a driver that does nothing.
+    ///
+    /// `f` must build the class the same way as the closure given to
+    /// `DeviceBuilder::add_class`. Its state goes into the `Scratch`, which
+    /// has room for 512 bytes:
+    ///
+    /// ```
+    /// let space = ClassSpace::measure(|builder, scratch| {
+    ///     HidReaderWriter::<_, 1, 1>::new(builder, scratch.alloc(hid::State::new()), config());
+    /// })
+    /// .with_control(REPORT_DESCRIPTOR.len())
+    /// .with_handlers(1);
+    /// ```
+    ///
+    /// A class with more than 512 bytes of descriptors panics here, as it
+    /// would in any `DeviceState` short of that size.
+    pub fn measure(f: impl for<'a> FnOnce(&mut Builder<'a, ScratchDriver>, &'a Scratch)) -> Self {
+        let empty = build_scratch(|_, _| {});
+        let class = build_scratch(f);
+        Self {
+            config: class.config - empty.config,
+            bos: class.bos - empty.bos,
+            msos: class.msos - empty.msos,
+            control: 0,
+            interfaces: class.interfaces,
+            handlers: 0,
+        }
+    }
+
+    /// Set the longest control transfer the class handles.
+    pub const fn with_control(mut self, len: usize) -> Self {
+        self.control = len;
+        self
+    }
+
+    /// Set the number of handlers the class registers.
+    pub const fn with_handlers(mut self, count: usize) -> Self {
+        self.handlers = count;
+        self
+    }
+
+    /// The room a CDC ACM serial port takes.
+    pub fn cdc_acm() -> Self {
+        Self::measure(|builder, scratch| {
+            CdcAcmClass::new(builder, scratch.alloc(State::new()), MAX_PACKET_SIZE as u16);
+        })
+        .with_control(CDC_ACM_CONTROL_LEN)
+        .with_handlers(1)
+    }
+}
+
+/// What a device built by `build_scratch` wrote.
+struct Written {
+    config: usize,
+    bos: usize,
+    msos: usize,
+    interfaces: usize,
+}
+
+/// Build a device with the classes added by `f` on scratch buffers, and
+/// return how much of them it used.
+fn build_scratch(f: impl for<'a> FnOnce(&mut Builder<'a, ScratchDriver>, &'a Scratch)) -> Written {
+    let scratch = Scratch::new();
+    let mut config_descriptor = [0; SCRATCH_CONFIG_LEN];
+    let mut bos_descriptor = [0; BOS_LEN];
+    let mut msos_descriptor = [0; MSOS_LEN];
+    let mut control_buf = [0; MAX_PACKET_SIZE as usize];
+    let mut builder = Builder::new(
+        ScratchDriver { next_index: 1 },
+        composite_config(Config::new(0, 0)),
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    f(&mut builder, &scratch);
+    let usage = builder.build().buffer_usage();
+    Written {
+        config: usage.config_descriptor_used,
+        bos: usage.bos_descriptor_used,
+        msos: usage.msos_descriptor_used,
+        interfaces: count_interfaces(&config_descriptor[..usage.config_descriptor_used]),
+    }
+}
+
+/// The number of interfaces in a configuration descriptor, not counting their
+/// alternate settings.
+fn count_interfaces(mut descriptor: &[u8]) -> usize {
+    let mut count = 0;
+    while let [len, kind, rest @ ..] = descriptor {
+        if *kind == INTERFACE_DESCRIPTOR && rest.get(1) == Some(&0) {
+            count += 1;
+        }
+        descriptor = descriptor.get(*len as usize..).unwrap_or(&[]);
+        if *len == 0 {
+            break;
+        }
+    }
+    count
+}
+
+/// Set up `config` for a composite device, as the logger's is.
+fn composite_config(mut config: Config<'_>) -> Config<'_> {
+    // Required for windows compatiblity.
+    // https://developer.nordicsemi.com/nRF_Connect_SDK/doc/1.9.1/kconfig/CONFIG_CDC_ACM_IAD.html#help
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+    config.max_packet_size_0 = MAX_PACKET_SIZE;
+    config
+}
+
+/// Room for the state of a class while `ClassSpace::measure` builds it.
+pub struct Scratch {
+    buf: UnsafeCell<[MaybeUninit<u8>; SCRATCH_STATE_LEN]>,
+    used: Cell<usize>,
+}
+
+impl Scratch {
+    fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([MaybeUninit::uninit(); SCRATCH_STATE_LEN]),
+            used: Cell::new(0),
+        }
+    }
+
+    /// Move `value` into the scratch space, where it stays until the
+    /// measurement is done. It is never dropped.
+    // Every call hands out a part of the buffer no other call does.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<T>(&self, value: T) -> &mut T {
+        let base = self.buf.get().cast::<u8>();
+        let start =
+            (base as usize + self.used.get()).next_multiple_of(align_of::<T>()) - base as usize;
+        let end = start + size_of::<T>();
+        assert!(end <= SCRATCH_STATE_LEN, "no scratch space left");
+        self.used.set(end);
+        // Safety: the bytes are aligned for `T`, inside the buffer and not
+        // handed out before.
+        unsafe {
+            let ptr = base.add(start).cast::<T>();
+            ptr.write(value);
+            &mut *ptr
+        }
+    }
+}
+
+/// A USB driver that hands out endpoints and does nothing else, for building
+/// a device only to measure it.
+pub struct ScratchDriver {
+    next_index: usize,
+}
+
+impl ScratchDriver {
+    fn info(
+        &mut self,
+        dir: Direction,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> EndpointInfo {
+        let index = self.next_index;
+        self.next_index += 1;
+        EndpointInfo {
+            addr: EndpointAddress::from_parts(index, dir),
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        }
+    }
+}
+
+impl<'a> Driver<'a> for ScratchDriver {
+    type EndpointOut = ScratchEndpoint;
+    type EndpointIn = ScratchEndpoint;
+    type ControlPipe = ScratchEndpoint;
+    type Bus = ScratchEndpoint;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<ScratchEndpoint, EndpointAllocError> {
+        Ok(ScratchEndpoint(self.info(
+            Direction::Out,
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        )))
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<ScratchEndpoint, EndpointAllocError> {
+        Ok(ScratchEndpoint(self.info(
+            Direction::In,
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        )))
+    }
+
+    fn start(mut self, control_max_packet_size: u16) -> (ScratchEndpoint, ScratchEndpoint) {
+        let info = self.info(
+            Direction::In,
+            EndpointType::Control,
+            control_max_packet_size,
+            0,
+        );
+        (ScratchEndpoint(info), ScratchEndpoint(info))
+    }
+}
+
+/// Every part of a `ScratchDriver`, none of which is ever used.
+pub struct ScratchEndpoint(EndpointInfo);
+
+impl driver::Endpoint for ScratchEndpoint {
+    fn info(&self) -> &EndpointInfo {
+        &self.0
+    }
+
+    async fn wait_enabled(&mut self) {
+        core::future::pending().await
+    }
+}
+
+impl driver::EndpointIn for ScratchEndpoint {
+    async fn write(&mut self, _buf: &[u8]) -> Result<(), EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+}
+
+impl driver::EndpointOut for ScratchEndpoint {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+}
+
+impl driver::ControlPipe for ScratchEndpoint {
+    fn max_packet_size(&self) -> usize {
+        self.0.max_packet_size as usize
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        core::future::pending().await
+    }
+
+    async fn data_out(
+        &mut self,
+        _buf: &mut [u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<usize, EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+
+    async fn data_in(
+        &mut self,
+        _data: &[u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<(), EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+
+    async fn accept(&mut self) {}
+
+    async fn reject(&mut self) {}
+
+    async fn accept_set_address(&mut self, _addr: u8) {}
+}
+
+impl driver::Bus for ScratchEndpoint {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        core::future::pending().await
+    }
+
+    fn endpoint_set_enabled(&mut self, _ep_addr: EndpointAddress, _enabled: bool) {}
+
+    fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+/// Buffers for the descriptors and control transfers of the device, and the
+/// state of the logger's serial port.
+///
+/// `CONFIG` is the size of the configuration descriptor buffer, which every
+/// class added takes room in. `CONTROL` is the size of the buffer for the data
+/// stage of control transfers, at least `MAX_PACKET_SIZE`. The defaults have
+/// room for more than the device can hold, as `embassy_usb` allows four
+/// interfaces and the logger takes two: one more serial port, or two classes
+/// of one interface each.
+pub struct DeviceState<'d, const CONFIG: usize = 256, const CONTROL: usize = 64> {
+    config_descriptor: [u8; CONFIG],
+    bos_descriptor: [u8; BOS_LEN],
+    msos_descriptor: [u8; MSOS_LEN],
+    control_buf: [u8; CONTROL],
+    serial_number: [u8; 16],
+    logger: State<'d>,
+    handler: Option<LinkHandler<'d>>,
+}
+
+impl<const CONFIG: usize, const CONTROL: usize> Default for DeviceState<'_, CONFIG, CONTROL> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const CONFIG: usize, const CONTROL: usize> DeviceState<'_, CONFIG, CONTROL> {
+    /// Create the state of a device.
+    pub fn new() -> Self {
+        const {
+            assert!(
+                CONTROL >= MAX_PACKET_SIZE as usize,
+                "control buffer below the packet size"
+            );
+        }
+        Self {
+            config_descriptor: [0; CONFIG],
+            bos_descriptor: [0; BOS_LEN],
+            msos_descriptor: [0; MSOS_LEN],
+            control_buf: [0; CONTROL],
+            serial_number: [0; 16],
+            logger: State::new(),
+            handler: None,
+        }
+    }
+}
+
+/// An error returned when adding a class to a `DeviceBuilder`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceError {
+    /// The configuration descriptor would take `needed` bytes, more than the
+    /// `available` in `DeviceState`.
+    DescriptorTooLarge { needed: usize, available: usize },
+    /// The BOS descriptor would take `needed` bytes, more than the
+    /// `available` in `DeviceState`.
+    BosTooLarge { needed: usize, available: usize },
+    /// The Microsoft OS descriptors would take `needed` bytes, more than the
+    /// `available` in `DeviceState`.
+    MsosTooLarge { needed: usize, available: usize },
+    /// A control transfer of `needed` bytes does not fit into the `available`
+    /// of the control buffer in `DeviceState`.
+    ControlBufferTooSmall { needed: usize, available: usize },
+    /// The device would have `needed` interfaces, more than the `available`
+    /// in `embassy_usb`.
+    TooManyInterfaces { needed: usize, available: usize },
+    /// The device would have `needed` handlers, more than the `available` in
+    /// `embassy_usb`.
+    TooManyHandlers { needed: usize, available: usize },
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DescriptorTooLarge { needed, available } => write!(
+                f,
+                "configuration descriptor needs {} bytes, {} available",
+                needed, available
+            ),
+            Self::BosTooLarge { needed, available } => write!(
+                f,
+                "BOS descriptor needs {} bytes, {} available",
+                needed, available
+            ),
+            Self::MsosTooLarge { needed, available } => write!(
+                f,
+                "Microsoft OS descriptors need {} bytes, {} available",
+                needed, available
+            ),
+            Self::ControlBufferTooSmall { needed, available } => write!(
+                f,
+                "control transfers need {} bytes, {} available",
+                needed, available
+            ),
+            Self::TooManyInterfaces { needed, available } => write!(
+                f,
+                "device needs {} interfaces, {} available",
+                needed, available
+            ),
+            Self::TooManyHandlers { needed, available } => write!(
+                f,
+                "device needs {} handlers, {} available",
+                needed, available
+            ),
+        }
+    }
+}
+
+/// Keeps count of the room taken in the buffers of `DeviceState` and of the
+/// interfaces and handlers of the device.
+struct DescriptorSpace {
+    config: usize,
+    bos: usize,
+    msos: usize,
+    interfaces: usize,
+    handlers: usize,
+    config_available: usize,
+    control_available: usize,
+}
+
+impl DescriptorSpace {
+    /// The room left after the logger's serial port.
+    fn new(config_available: usize, control_available: usize) -> Self {
+        let empty = build_scratch(|_, _| {});
+        let port = ClassSpace::cdc_acm();
+        Self {
+            config: empty.config + port.config,
+            bos: empty.bos + port.bos,
+            msos: empty.msos + port.msos,
+            interfaces: port.interfaces,
+            handlers: port.handlers + LINK_HANDLERS,
+            config_available,
+            control_available,
+        }
+    }
+
+    /// Take room for a function, or none if any of it does not fit.
+    fn reserve(&mut self, space: ClassSpace) -> Result<(), DeviceError> {
+        let config = self.config + space.config;
+        if config > self.config_available {
+            return Err(DeviceError::DescriptorTooLarge {
+                needed: config,
+                available: self.config_available,
+            });
+        }
+        let bos = self.bos + space.bos;
+        if bos > BOS_LEN {
+            return Err(DeviceError::BosTooLarge {
+                needed: bos,
+                available: BOS_LEN,
+            });
+        }
+        let msos = self.msos + space.msos;
+        if msos > MSOS_LEN {
+            return Err(DeviceError::MsosTooLarge {
+                needed: msos,
+                available: MSOS_LEN,
+            });
+        }
+        if space.control > self.control_available {
+            return Err(DeviceError::ControlBufferTooSmall {
+                needed: space.control,
+                available: self.control_available,
+            });
+        }
+        let interfaces = self.interfaces + space.interfaces;
+        if interfaces > MAX_INTERFACES {
+            return Err(DeviceError::TooManyInterfaces {
+                needed: interfaces,
+                available: MAX_INTERFACES,
+            });
+        }
+        let handlers = self.handlers + space.handlers;
+        if handlers > MAX_HANDLERS {
+            return Err(DeviceError::TooManyHandlers {
+                needed: handlers,
+                available: MAX_HANDLERS,
+            });
+        }
+        self.config = config;
+        self.bos = bos;
+        self.msos = msos;
+        self.interfaces = interfaces;
+        self.handlers = handlers;
+        Ok(())
+    }
+}
+
+/// Builds a USB device with the logger's serial port as its first function.
+pub struct DeviceBuilder<'d, D: Driver<'d>, const N: usize> {
+    builder: Builder<'d, D>,
+    logger: &'d UsbLogger<N>,
+    class: CdcAcmClass<'d, D>,
+    space: DescriptorSpace,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> DeviceBuilder<'d, D, N> {
+    /// Start a device with the identity from `logger_config` and add the
+    /// logger's serial port.
+    pub fn new<const CONFIG: usize, const CONTROL: usize>(
+        driver: D,
+        logger_config: LoggerConfig<'d>,
+        state: &'d mut DeviceState<'d, CONFIG, CONTROL>,
+        logger: &'d UsbLogger<N>,
+    ) -> Self {
+        let mut config = Config::new(logger_config.vendor_id, logger_config.product_id);
+        config.manufacturer = logger_config.manufacturer;
+        config.product = logger_config.product;
+        config.serial_number = match logger_config.serial_number {
+            SerialNumber::UniqueId(uid) => {
+                crate::config::format_unique_id(&uid, &mut state.serial_number)
+            }
+            SerialNumber::Custom(serial_number) => Some(serial_number),
+            SerialNumber::Omit => None,
+        };
+        config.max_power = logger_config.max_power;
+
+        let space = DescriptorSpace::new(CONFIG, CONTROL);
+        assert!(
+            space.config <= CONFIG,
+            "no room for the logger's descriptors"
+        );
+        let mut builder = Builder::new(
+            driver,
+            composite_config(config),
+            &mut state.config_descriptor,
+            &mut state.bos_descriptor,
+            &mut state.msos_descriptor,
+            &mut state.control_buf,
+        );
+
+        builder.handler(state.handler.insert(LinkHandler(&logger.link)));
+
+        let class = CdcAcmClass::new(&mut builder, &mut state.logger, MAX_PACKET_SIZE as u16);
+        Self {
+            builder,
+            logger,
+            class,
+            space,
+        }
+    }
+
+    /// Add a function built by `f` on the underlying builder.
+    ///
+    /// `space` is the room the function takes, measured by building the same
+    /// class with `ClassSpace::measure`, as `embassy_usb` panics if a buffer
+    /// overflows or it runs out of interfaces or handlers. `f` is only called
+    /// if it fits.
+    pub fn add_class<T>(
+        &mut self,
+        space: ClassSpace,
+        f: impl FnOnce(&mut Builder<'d, D>) -> T,
+    ) -> Result<T, DeviceError> {
+        self.space.reserve(space)?;
+        Ok(f(&mut self.builder))
+    }
+
+    /// Add a serial port for the application.
+    pub fn add_serial_port(
+        &mut self,
+        state: &'d mut State<'d>,
+    ) -> Result<CdcAcmClass<'d, D>, DeviceError> {
+        self.add_class(ClassSpace::cdc_acm(), |builder| {
+            CdcAcmClass::new(builder, state, MAX_PACKET_SIZE as u16)
+        })
+    }
+
+    /// Finish the device.
+    pub fn build(self) -> CompositeDevice<'d, D, N> {
+        let (sender, receiver, control) = self.class.split_with_control();
+        let device = self.builder.build();
+        debug_assert_eq!(
+            device.buffer_usage().config_descriptor_used,
+            self.space.config,
+            "a class wrote other descriptors than were measured"
+        );
+        CompositeDevice {
+            device,
+            logger: self.logger,
+            sender,
+            receiver,
+            control,
+        }
+    }
+}
+
+/// A USB device built by `DeviceBuilder`, which runs the bus and the logger.
+pub struct CompositeDevice<'d, D: Driver<'d>, const N: usize> {
+    device: UsbDevice<'d, D>,
+    logger: &'d UsbLogger<N>,
+    sender: Sender<'d, D>,
+    receiver: Receiver<'d, D>,
+    control: ControlChanged<'d>,
+}
+
+impl<'d, D: Driver<'d>, const N: usize> CompositeDevice<'d, D, N> {
+    /// Run the device and the logger. Never returns.
+    ///
+    /// The classes added by the application are run by their own futures.
+    pub async fn run(&mut self) -> ! {
+        loop {
+            let run_fut = self.device.run();
+            let class_fut =
+                self.logger
+                    .run_logger_class(&mut self.sender, &mut self.receiver, &self.control);
+            join(run_fut, class_fut).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::select;
+    use embassy_usb::class::hid::{self, HidReaderWriter};
+
+    use super::*;
+    use crate::mock::{Host, MockDriver};
+
+    /// A standard GET_DESCRIPTOR request for the configuration descriptor.
+    const GET_CONFIGURATION: [u8; 8] = [0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xff, 0x00];
+
+    /// A vendor defined report of one byte each way.
+    const REPORT_DESCRIPTOR: &[u8] = &[
+        0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0x15, 0x00, 0x26, 0xff, 0x00, 0x75, 0x08, 0x95,
+        0x01, 0x09, 0x01, 0x81, 0x02, 0x09, 0x01, 0x91, 0x02, 0xc0,
+    ];
+
+    /// Build a device with `add` and return the configuration descriptor it
+    /// sends to the host.
+    fn configuration(add: impl FnOnce(&mut DeviceBuilder<'_, MockDriver, 64>)) -> Vec<u8> {
+        let host = Rc::new(RefCell::new(Host {
+            setup: vec![GET_CONFIGURATION],
+            ..Host::default()
+        }));
+        let logger = UsbLogger::<64>::new();
+        let mut state: DeviceState = DeviceState::new();
+        let mut builder = DeviceBuilder::new(
+            MockDriver::new(&host),
+            LoggerConfig::new(),
+            &mut state,
+            &logger,
+        );
+        add(&mut builder);
+        let mut device = builder.build();
+        let answered = core::future::poll_fn(|_| match host.borrow().control_in.len() {
+            0 => core::task::Poll::Pending,
+            _ => core::task::Poll::Ready(()),
+        });
+        block_on(select(device.run(), answered));
+        let descriptor = host.borrow().control_in.clone();
+        descriptor
+    }
+
+    /// The total length stated in a configuration descriptor.
+    fn total_len(descriptor: &[u8]) -> usize {
+        u16::from_le_bytes([descriptor[2], descriptor[3]]) as usize
+    }
+
+    /// The configuration descriptor length of a device with only the logger.
+    fn logger_device_len() -> usize {
+        space().config
+    }
+
+    #[test]
+    fn logger_device_len_matches_the_descriptor() {
+        let descriptor = configuration(|_| {});
+        assert_eq!(descriptor.len(), logger_device_len());
+        assert_eq!(total_len(&descriptor), logger_device_len());
+    }
+
+    fn add_serial_port(builder: &mut DeviceBuilder<'_, MockDriver, 64>) -> Result<(), DeviceError> {
+        let port = Box::leak(Box::new(State::new()));
+        builder.add_serial_port(port).map(drop)
+    }
+
+    fn hid_config<'d>() -> hid::Config<'d> {
+        hid::Config {
+            report_descriptor: REPORT_DESCRIPTOR,
+            request_handler: None,
+            poll_ms: 10,
+            max_packet_size: 8,
+        }
+    }
+
+    /// The room a HID class with `REPORT_DESCRIPTOR` takes.
+    fn hid_space() -> ClassSpace {
+        ClassSpace::measure(|builder, scratch| {
+            HidReaderWriter::<_, 1, 1>::new(
+                builder,
+                scratch.alloc(hid::State::new()),
+                hid_config(),
+            );
+        })
+        .with_control(REPORT_DESCRIPTOR.len())
+        .with_handlers(1)
+    }
+
+    fn add_hid(builder: &mut DeviceBuilder<'_, MockDriver, 64>) -> Result<(), DeviceError> {
+        builder
+            .add_class(hid_space(), |builder| {
+                let state = Box::leak(Box::new(hid::State::new()));
+                HidReaderWriter::<_, 1, 1>::new(builder, state, hid_config())
+            })
+            .map(drop)
+    }
+
+    #[test]
+    fn classes_are_measured() {
+        let cdc = ClassSpace::cdc_acm();
+        // The interface association, the communication interface with its
+        // header, ACM and union descriptors and interrupt endpoint, and the
+        // data interface with its two bulk endpoints.
+        assert_eq!((cdc.config, cdc.interfaces), (8 + 53, 2));
+        let hid = hid_space();
+        assert_eq!((hid.config, hid.interfaces), (8 + 32, 1));
+        assert_eq!((hid.bos, hid.msos), (0, 0));
+    }
+
+    #[test]
+    fn serial_port_len_matches_the_descriptor() {
+        let descriptor = configuration(|builder| add_serial_port(builder).unwrap());
+        let expected = logger_device_len() + ClassSpace::cdc_acm().config;
+        assert_eq!(descriptor.len(), expected);
+        assert_eq!(total_len(&descriptor), expected);
+    }
+
+    #[test]
+    fn hid_len_matches_the_descriptor() {
+        let descriptor = configuration(|builder| {
+            add_hid(builder).unwrap();
+            add_hid(builder).unwrap();
+        });
+        let expected = logger_device_len() + 2 * hid_space().config;
+        assert_eq!(descriptor.len(), expected);
+        assert_eq!(total_len(&descriptor), expected);
+    }
+
+    #[test]
+    fn default_device_fits_one_more_serial_port() {
+        let descriptor = configuration(|builder| {
+            add_serial_port(builder).unwrap();
+            assert_eq!(
+                add_serial_port(builder),
+                Err(DeviceError::TooManyInterfaces {
+                    needed: 6,
+                    available: MAX_INTERFACES
+                })
+            );
+            assert_eq!(
+                add_hid(builder),
+                Err(DeviceError::TooManyInterfaces {
+                    needed: 5,
+                    available: MAX_INTERFACES
+                })
+            );
+        });
+        assert_eq!(
+            total_len(&descriptor),
+            logger_device_len() + ClassSpace::cdc_acm().config
+        );
+    }
+
+    fn space() -> DescriptorSpace {
+        DescriptorSpace::new(256, 64)
+    }
+
+    /// A class of one interface with `config` bytes of descriptors.
+    fn class(config: usize) -> ClassSpace {
+        ClassSpace {
+            config,
+            bos: 0,
+            msos: 0,
+            control: 0,
+            interfaces: 1,
+            handlers: 0,
+        }
+    }
+
+    #[test]
+    fn handlers_are_counted() {
+        let mut space = space();
+        let handlers = ClassSpace {
+            interfaces: 0,
+            ..class(9)
+        }
+        .with_handlers(2);
+        assert_eq!(space.reserve(handlers), Ok(()));
+        assert_eq!(
+            space.reserve(handlers),
+            Err(DeviceError::TooManyHandlers {
+                needed: 6,
+                available: MAX_HANDLERS
+            })
+        );
+    }
+
+    #[test]
+    fn class_that_does_not_fit_takes_no_room() {
+        let mut space = space();
+        let bos = space.bos;
+        assert!(space.reserve(class(200)).is_err());
+        assert_eq!(
+            space.reserve(ClassSpace {
+                bos: BOS_LEN,
+                ..class(8)
+            }),
+            Err(DeviceError::BosTooLarge {
+                needed: bos + BOS_LEN,
+                available: BOS_LEN
+            })
+        );
+        assert_eq!(
+            space.reserve(class(8).with_control(65)),
+            Err(DeviceError::ControlBufferTooSmall {
+                needed: 65,
+                available: 64
+            })
+        );
+        assert_eq!(space.reserve(class(40)), Ok(()));
+        assert_eq!(space.config, logger_device_len() + 40);
+        assert_eq!(space.bos, bos);
+    }
+
+    #[test]
+    fn msos_descriptors_are_counted() {
+        let mut space = space();
+        let vendor = ClassSpace {
+            msos: 160,
+            ..class(9)
+        };
+        assert_eq!(space.reserve(vendor), Ok(()));
+        assert_eq!(
+            space.reserve(vendor),
+            Err(DeviceError::MsosTooLarge {
+                needed: 320,
+                available: MSOS_LEN
+            })
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! env_logger-style filtering of log records by target.
+//!
+//! A filter is parsed from a comma separated list of directives such as
+//! `main=debug,mcp=trace,warn`. Each directive is either a bare level, which
+//! sets the default, a bare target, which enables everything for that target,
+//! or `target=level`. When several targets match, the longest one wins.
+
+use core::fmt;
+use core::str::FromStr;
+
+use log::{LevelFilter, Metadata};
+
+/// The maximum number of `target=level` directives in a filter.
+pub const MAX_DIRECTIVES: usize = 8;
+
+/// The maximum length of a target in a directive.
+pub const MAX_TARGET_LEN: usize = 32;
+
+/// Errors returned when parsing a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterError {
+    /// More than `MAX_DIRECTIVES` targets were given.
+    TooManyDirectives,
+    /// A target was longer than `MAX_TARGET_LEN` bytes.
+    TargetTooLong,
+    /// A level was not one of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    InvalidLevel,
+    /// A directive had a level but no target, as in `=debug`.
+    MissingTarget,
+    /// The filter had no directives at all.
+    Empty,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FilterError::TooManyDirectives => "too many directives",
+            FilterError::TargetTooLong => "target too long",
+            FilterError::InvalidLevel => "invalid level",
+            FilterError::MissingTarget => "missing target",
+            FilterError::Empty => "no directives",
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Directive {
+    target: [u8; MAX_TARGET_LEN],
+    len: usize,
+    level: LevelFilter,
+}
+
+impl Directive {
+    fn target(&self) -> &str {
+        // Only ever filled from a `&str` on a char boundary.
+        core::str::from_utf8(&self.target[..self.len]).unwrap_or("")
+    }
+}
+
+/// A table of per-target level filters with a default level.
+#[derive(Clone, Copy)]
+pub struct Filter {
+    default: LevelFilter,
+    directives: [Option<Directive>; MAX_DIRECTIVES],
+}
+
+impl Filter {
+    /// Create a filter that applies `default` to every target.
+    pub const fn new(default: LevelFilter) -> Self {
+        Self {
+            default,
+            directives: [None; MAX_DIRECTIVES],
+        }
+    }
+
+    /// Parse a filter from an env_logger-style directive string.
+    ///
+    /// Targets that are not matched by any directive are disabled unless a bare
+    /// level is given. A spec without any directives is rejected rather than
+    /// disabling everything.
+    pub fn parse(spec: &str) -> Result<Self, FilterError> {
+        let mut filter = Self::new(LevelFilter::Off);
+        let parts = spec.split(',').map(str::trim).filter(|p| !p.is_empty());
+        if parts.clone().next().is_none() {
+            return Err(FilterError::Empty);
+        }
+        for part in parts {
+            match part.split_once('=') {
+                Some((target, level)) => {
+                    let target = target.trim();
+                    if target.is_empty() {
+                        return Err(FilterError::MissingTarget);
+                    }
+                    let level = parse_level(level.trim())?;
+                    filter.set(target, level)?;
+                }
+                None => match parse_level(part) {
+                    Ok(level) => filter.default = level,
+                    Err(_) => filter.set(part, LevelFilter::Trace)?,
+                },
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Set the level of a target, replacing any existing directive for it.
+    pub fn set(&mut self, target: &str, level: LevelFilter) -> Result<(), FilterError> {
+        if target.len() > MAX_TARGET_LEN {
+            return Err(FilterError::TargetTooLong);
+        }
+        let slot = match self
+            .directives
+            .iter()
+            .position(|d| matches!(d, Some(d) if d.target() == target))
+        {
+            Some(i) => i,
+            None => self
+                .directives
+                .iter()
+                .position(Option::is_none)
+                .ok_or(FilterError::TooManyDirectives)?,
+        };
+        let mut directive = Directive {
+            target: [0; MAX_TARGET_LEN],
+            len: target.len(),
+            level,
+        };
+        directive.target[..target.len()].copy_from_slice(target.as_bytes());
+        self.directives[slot] = Some(directive);
+        Ok(())
+    }
+
+    /// The level applied to records with the given target.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives
+            .iter()
+            .flatten()
+            .filter(|d| target.starts_with(d.target()))
+            .max_by_key(|d| d.len)
+            .map_or(self.default, |d| d.level)
+    }
+
+    /// Whether a record with the given metadata passes the filter.
+    pub fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    /// Whether every target is logged at the default level.
+    pub(crate) fn is_uniform(&self) -> bool {
+        self.directives.iter().all(Option::is_none)
+    }
+
+    /// The most verbose level any target can be logged at.
+    pub fn max_level(&self) -> LevelFilter {
+        self.directives
+            .iter()
+            .flatten()
+            .map(|d| d.level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new(LevelFilter::Trace)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for d in self.directives.iter().flatten() {
+            write!(f, "{}={},", d.target(), level_name(d.level))?;
+        }
+        f.write_str(level_name(self.default))
+    }
+}
+
+fn level_name(level: LevelFilter) -> &'static str {
+    match level {
+        LevelFilter::Off => "off",
+        LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug => "debug",
+        LevelFilter::Trace => "trace",
+    }
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, FilterError> {
+    LevelFilter::from_str(s).map_err(|_| FilterError::InvalidLevel)
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    fn enabled(filter: &Filter, level: Level, target: &str) -> bool {
+        filter.enabled(&Metadata::builder().level(level).target(target).build())
+    }
+
+    #[test]
+    fn bare_level_sets_the_default() {
+        let filter = Filter::parse("info").unwrap();
+        assert!(enabled(&filter, Level::Info, "main"));
+        assert!(!enabled(&filter, Level::Debug, "main"));
+        assert_eq!(filter.max_level(), LevelFilter::Info);
+    }
+
+    #[test]
+    fn unmatched_targets_are_off_without_a_bare_level() {
+        let filter = Filter::parse("mcp=debug").unwrap();
+        assert!(enabled(&filter, Level::Debug, "mcp"));
+        assert!(!enabled(&filter, Level::Error, "main"));
+    }
+
+    #[test]
+    fn bare_target_enables_everything_for_it() {
+        let filter = Filter::parse("warn, mcp ").unwrap();
+        assert!(enabled(&filter, Level::Trace, "mcp::pins"));
+        assert!(!enabled(&filter, Level::Info, "main"));
+    }
+
+    #[test]
+    fn longest_matching_target_wins() {
+        let filter = Filter::parse("mcp=warn,mcp::pins=trace,info").unwrap();
+        assert!(enabled(&filter, Level::Trace, "mcp::pins"));
+        assert!(!enabled(&filter, Level::Info, "mcp::bus"));
+        assert!(enabled(&filter, Level::Info, "main"));
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn later_directive_for_a_target_replaces_the_earlier_one() {
+        let filter = Filter::parse("main=trace,main=error").unwrap();
+        assert!(!enabled(&filter, Level::Warn, "main"));
+        assert_eq!(filter.to_string(), "main=error,off");
+    }
+
+    #[test]
+    fn invalid_directives_are_rejected() {
+        assert_eq!(
+            Filter::parse("main=loud").err(),
+            Some(FilterError::InvalidLevel)
+        );
+        assert_eq!(
+            Filter::parse("=debug").err(),
+            Some(FilterError::MissingTarget)
+        );
+        let long = "x".repeat(MAX_TARGET_LEN + 1);
+        assert_eq!(Filter::parse(&long).err(), Some(FilterError::TargetTooLong));
+        let many: std::vec::Vec<_> = (0..=MAX_DIRECTIVES)
+            .map(|i| std::format!("t{}=info", i))
+            .collect();
+        assert_eq!(
+            Filter::parse(&many.join(",")).err(),
+            Some(FilterError::TooManyDirectives)
+        );
+    }
+
+    #[test]
+    fn empty_spec_is_rejected() {
+        assert_eq!(Filter::parse("").err(), Some(FilterError::Empty));
+        assert_eq!(Filter::parse(" , ").err(), Some(FilterError::Empty));
+    }
+}
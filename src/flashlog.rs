@@ -0,0 +1,687 @@
+//! A log sink appending records to a circular log in a flash partition.
+//!
+//! The partition is split into erase sectors, each starting with a header
+//! holding a sequence number that grows by one for every sector started.
+//! Records are appended to the newest sector behind a two byte length and a
+//! CRC of their contents. Once it is full, the oldest sector is erased and
+//! becomes the newest, so every sector is erased once per lap around the
+//! partition and an existing log is continued after a reset rather than
+//! started over.
+//!
+//! Records that are queued together are written in one flash operation, of
+//! up to `BATCH_LEN` bytes, so a burst of records stalls the chip once rather
+//! than once per record. A sector is erased once per `ERASE_SIZE` bytes of
+//! records.
+//!
+//! A record cut short by a power loss fails its CRC and is skipped. If the
+//! damage reaches into the length, the rest of that sector is given up and the
+//! log continues in the next one.
+//!
+//! Every record written wears the flash and stalls the chip, so register the
+//! sink at a level that only lets rare records through, such as warnings.
+//!
+//! The partition is `FLASHLOG` in `memory.x`. Register `dump_command` on the
+//! console to print the log over USB:
+//!
+//! ```
+//! static FLASH_LOG: FlashSink<1024> = FlashSink::new();
+//!
+//! sink::add(&FLASH_LOG, LevelFilter::Warn).unwrap();
+//! console::register("flashlog", "print the log kept in flash", flashlog::dump_command).unwrap();
+//! spawner.spawn(flash_log_task(Flash::new_blocking(p.FLASH))).unwrap();
+//!
+//! // In `flash_log_task`:
+//! FLASH_LOG.run(flash, flashlog::partition(), &USB_LOGGER).await
+//! ```
+
+use core::fmt::{self, Write};
+use core::ops::Range;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+use log::Record;
+
+use crate::format::Style;
+use crate::line::Line;
+use crate::overflow::DropStats;
+use crate::sink::{RecordQueue, Sink};
+use crate::{UsbLogger, CS, MAX_RECORD_LEN};
+
+/// Marks a sector header, "LOG1".
+const SECTOR_MAGIC: u32 = 0x4c4f_4731;
+
+/// The magic, the sequence number and its complement.
+const SECTOR_HEADER_LEN: usize = 12;
+
+/// The record length and the CRC of its contents.
+const RECORD_HEADER_LEN: usize = 4;
+
+/// The length of erased flash, marking the end of a sector's records.
+const ERASED_LEN: u16 = 0xffff;
+
+/// The largest flash write size supported, which records are padded to.
+const MAX_WRITE_SIZE: usize = 16;
+
+/// The most bytes of records written in one flash operation.
+const BATCH_LEN: usize = 512;
+
+/// Written when the log is opened, so the boots can be told apart.
+const BOOT_MARKER: &[u8] = b"--- boot ---\r\n";
+
+/// Requests from the console to print the log.
+static DUMP: Signal<CS, ()> = Signal::new();
+
+/// The address of the flash in the memory map.
+#[cfg(target_os = "none")]
+const XIP_BASE: u32 = 0x1000_0000;
+
+/// Errors returned when opening the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashLogError {
+    /// The partition is not made of at least two whole erase sectors, or the
+    /// flash cannot be read and written in single bytes and small blocks.
+    Partition,
+    /// The flash reported an error.
+    Flash(NorFlashErrorKind),
+}
+
+impl fmt::Display for FlashLogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashLogError::Partition => f.write_str("unsupported partition"),
+            FlashLogError::Flash(kind) => write!(f, "flash error: {}", kind),
+        }
+    }
+}
+
+impl<E: NorFlashError> From<E> for FlashLogError {
+    fn from(e: E) -> Self {
+        FlashLogError::Flash(e.kind())
+    }
+}
+
+/// Returns the `FLASHLOG` partition from `memory.x`, as offsets into the flash.
+#[cfg(target_os = "none")]
+pub fn partition() -> Range<u32> {
+    extern "C" {
+        static __flashlog_start: u8;
+        static __flashlog_end: u8;
+    }
+    // Safety: only the addresses of the linker symbols are used.
+    let (start, end) = unsafe {
+        (
+            core::ptr::addr_of!(__flashlog_start) as u32,
+            core::ptr::addr_of!(__flashlog_end) as u32,
+        )
+    };
+    start - XIP_BASE..end - XIP_BASE
+}
+
+/// A console handler that prints the log kept in flash.
+///
+/// The log is printed by `FlashSink::run`, after the command has returned.
+pub fn dump_command(_args: &str, _out: &mut dyn Write) {
+    DUMP.signal(());
+}
+
+/// Appends records to a circular log in flash through a buffer of `N` bytes.
+///
+/// The buffer is drained by `run`, which must be running for anything to be
+/// written.
+///
+/// On the RP2040 every flash erase and write runs with interrupts disabled
+/// and the other core paused, as the flash cannot be read while it is busy.
+/// A sector erase takes tens of milliseconds, up to 400 ms on the Pico's
+/// flash, and nothing else runs in that time, including interrupt executors
+/// such as the one running the USB logger. Size the USB logger's buffer to
+/// ride out an erase if records must not be dropped meanwhile.
+pub struct FlashSink<const N: usize> {
+    queue: RecordQueue<N>,
+    style: Style,
+}
+
+impl<const N: usize> Default for FlashSink<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FlashSink<N> {
+    /// Create a sink using the compact style.
+    pub const fn new() -> Self {
+        Self {
+            queue: RecordQueue::new(),
+            style: Style::Compact,
+        }
+    }
+
+    /// Set the layout of the records.
+    pub const fn with_style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Returns the number of bytes and records lost to buffer overflows and
+    /// failed flash writes so far.
+    pub fn dropped(&self) -> DropStats {
+        self.queue.drops.stats()
+    }
+
+    /// Write buffered records to the log in `partition` of `flash`, and print
+    /// the log through `usb` when `dump_command` is run. Never returns.
+    ///
+    /// Flash operations stall the whole chip, see `FlashSink`, so this should
+    /// run at a low priority. If the log cannot be opened, the error is logged
+    /// and records are left in the buffer.
+    pub async fn run<F: NorFlash, const M: usize>(
+        &self,
+        flash: F,
+        partition: Range<u32>,
+        usb: &UsbLogger<M>,
+    ) -> ! {
+        let mut log = match FlashLog::open(flash, partition) {
+            Ok(log) => log,
+            Err(e) => {
+                log::error!("flash log unavailable: {}", e);
+                core::future::pending().await
+            }
+        };
+        let _ = log.append(BOOT_MARKER);
+        let mut buf = [0; MAX_RECORD_LEN];
+        loop {
+            let lost = core::mem::take(&mut log.lost);
+            if lost.records > 0 {
+                self.queue.drops.add(lost.bytes as usize, lost.records);
+            }
+            // `pop` only waits before it has taken anything, so a dump request
+            // never loses a record.
+            match select(self.queue.pop(&mut buf), DUMP.wait()).await {
+                Either::First(len) => {
+                    let _ = log.stage(&buf[..len]);
+                    // Records queued in the meantime go into the same write.
+                    while log.batch_len + MAX_RECORD_SIZE <= BATCH_LEN {
+                        let Some(len) = self.queue.try_pop(&mut buf) else {
+                            break;
+                        };
+                        let _ = log.stage(&buf[..len]);
+                    }
+                    let _ = log.sync();
+                    self.queue.done();
+                }
+                Either::Second(()) => log.dump(usb).await,
+            }
+        }
+    }
+
+    /// Wait until everything logged so far has been written to flash.
+    ///
+    /// Returns false if that did not happen within `timeout`.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        self.queue.flush(timeout).await
+    }
+}
+
+impl<const N: usize> Sink for FlashSink<N> {
+    fn write(&self, record: &Record) {
+        let mut line = Line::new();
+        let _ = self.style.write(record, &mut line);
+        self.queue.push(line.bytes());
+    }
+}
+
+/// The position of a record in the log.
+#[derive(Clone, Copy)]
+struct Cursor {
+    sector: u32,
+    pos: u32,
+}
+
+/// What was found at a position in a sector.
+enum Entry {
+    /// A record of `len` bytes, which failed its CRC if not `valid`.
+    Record { len: usize, valid: bool },
+    /// Erased flash, or a damaged length.
+    End,
+}
+
+/// The log in a partition of `F`.
+struct FlashLog<F: NorFlash> {
+    flash: F,
+    start: u32,
+    sectors: u32,
+    /// The sector records are appended to and its sequence number.
+    head: Option<(u32, u32)>,
+    /// Where the next record goes in the head sector.
+    pos: u32,
+    /// Records encoded for `pos` onwards, waiting to be written.
+    batch: [u8; BATCH_LEN],
+    batch_len: usize,
+    /// The records in `batch`.
+    batched: DropStats,
+    /// Records lost to flash errors, not yet counted by the sink.
+    lost: DropStats,
+}
+
+impl<F: NorFlash> FlashLog<F> {
+    const SECTOR_SIZE: u32 = F::ERASE_SIZE as u32;
+
+    /// Where the records of a sector start.
+    const FIRST_RECORD: u32 = align(SECTOR_HEADER_LEN, F::WRITE_SIZE) as u32;
+
+    /// Open the log in `partition`, continuing after its newest record.
+    fn open(flash: F, partition: Range<u32>) -> Result<Self, FlashLogError> {
+        let len = partition.end.saturating_sub(partition.start);
+        if F::READ_SIZE != 1
+            || F::WRITE_SIZE > MAX_WRITE_SIZE
+            || !partition.start.is_multiple_of(Self::SECTOR_SIZE)
+            || !len.is_multiple_of(Self::SECTOR_SIZE)
+            || len / Self::SECTOR_SIZE < 2
+        {
+            return Err(FlashLogError::Partition);
+        }
+        let mut log = Self {
+            flash,
+            start: partition.start,
+            sectors: len / Self::SECTOR_SIZE,
+            head: None,
+            pos: 0,
+            batch: [0xff; BATCH_LEN],
+            batch_len: 0,
+            batched: DropStats::default(),
+            lost: DropStats::default(),
+        };
+        for sector in 0..log.sectors {
+            if let Some(seq) = log.sequence(sector)? {
+                if log.head.is_none_or(|(_, head)| seq > head) {
+                    log.head = Some((sector, seq));
+                }
+            }
+        }
+        if let Some((sector, _)) = log.head {
+            log.pos = log.recover(sector)?;
+        }
+        Ok(log)
+    }
+
+    fn address(&self, sector: u32, pos: u32) -> u32 {
+        self.start + sector * Self::SECTOR_SIZE + pos
+    }
+
+    /// The sequence number of a sector, if it has a valid header.
+    fn sequence(&mut self, sector: u32) -> Result<Option<u32>, FlashLogError> {
+        let mut header = [0; SECTOR_HEADER_LEN];
+        self.flash.read(self.address(sector, 0), &mut header)?;
+        let word = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let valid = word(0) == SECTOR_MAGIC && word(4) == !word(8);
+        Ok(valid.then_some(word(4)))
+    }
+
+    /// Find where the next record goes in the head sector after a reset.
+    ///
+    /// Returns the end of the sector if it cannot take any more records.
+    fn recover(&mut self, sector: u32) -> Result<u32, FlashLogError> {
+        let mut cursor = Cursor {
+            sector,
+            pos: Self::FIRST_RECORD,
+        };
+        while let Entry::Record { len, .. } = self.entry(cursor, None)? {
+            cursor.pos += record_size(len, F::WRITE_SIZE);
+        }
+        // A record that was being written when the power failed may have
+        // left bytes behind an erased length.
+        let mut chunk = [0; 64];
+        let mut pos = cursor.pos;
+        while pos < Self::SECTOR_SIZE {
+            let n = chunk.len().min((Self::SECTOR_SIZE - pos) as usize);
+            self.flash
+                .read(self.address(sector, pos), &mut chunk[..n])?;
+            if chunk[..n].iter().any(|&b| b != 0xff) {
+                return Ok(Self::SECTOR_SIZE);
+            }
+            pos += n as u32;
+        }
+        Ok(cursor.pos)
+    }
+
+    /// Read the entry at `cursor`, and check a record's CRC if `buf` is given
+    /// to copy its contents into.
+    fn entry(
+        &mut self,
+        cursor: Cursor,
+        buf: Option<&mut [u8; MAX_RECORD_LEN]>,
+    ) -> Result<Entry, FlashLogError> {
+        if cursor.pos as usize + RECORD_HEADER_LEN > Self::SECTOR_SIZE as usize {
+            return Ok(Entry::End);
+        }
+        let mut header = [0; RECORD_HEADER_LEN];
+        let address = self.address(cursor.sector, cursor.pos);
+        self.flash.read(address, &mut header)?;
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        let size = record_size(len as usize, F::WRITE_SIZE);
+        if len == ERASED_LEN
+            || len as usize > MAX_RECORD_LEN
+            || cursor.pos + size > Self::SECTOR_SIZE
+        {
+            return Ok(Entry::End);
+        }
+        let len = len as usize;
+        let valid = match buf {
+            Some(buf) => {
+                let contents = &mut buf[..len];
+                self.flash
+                    .read(address + RECORD_HEADER_LEN as u32, contents)?;
+                crc16(contents) == u16::from_le_bytes([header[2], header[3]])
+            }
+            None => true,
+        };
+        Ok(Entry::Record { len, valid })
+    }
+
+    /// Append a record and write it out.
+    fn append(&mut self, record: &[u8]) -> Result<(), FlashLogError> {
+        self.stage(record)?;
+        self.sync()
+    }
+
+    /// Add a record to the batch, writing out the batch first if the record
+    /// does not fit into it, and starting a new sector if it does not fit
+    /// into the head sector.
+    fn stage(&mut self, record: &[u8]) -> Result<(), FlashLogError> {
+        let len = record.len().min(MAX_RECORD_LEN);
+        let size = record_size(len, F::WRITE_SIZE) as usize;
+        let end = self.pos as usize + self.batch_len + size;
+        if self.batch_len + size > BATCH_LEN || end > Self::SECTOR_SIZE as usize {
+            self.sync()?;
+        }
+        if self.head.is_none() || self.pos + size as u32 > Self::SECTOR_SIZE {
+            if let Err(e) = self.advance() {
+                self.lose(len, 1);
+                return Err(e);
+            }
+        }
+        let buf = &mut self.batch[self.batch_len..self.batch_len + size];
+        buf.fill(0xff);
+        buf[..2].copy_from_slice(&(len as u16).to_le_bytes());
+        buf[2..4].copy_from_slice(&crc16(&record[..len]).to_le_bytes());
+        buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len].copy_from_slice(&record[..len]);
+        self.batch_len += size;
+        self.batched.bytes += len as u32;
+        self.batched.records += 1;
+        Ok(())
+    }
+
+    /// Write the batch to the head sector in one operation.
+    fn sync(&mut self) -> Result<(), FlashLogError> {
+        let len = core::mem::take(&mut self.batch_len);
+        let batched = core::mem::take(&mut self.batched);
+        let Some((sector, _)) = self.head.filter(|_| len > 0) else {
+            return Ok(());
+        };
+        let address = self.address(sector, self.pos);
+        // The space is used up even if the write fails part way.
+        self.pos += len as u32;
+        if let Err(e) = self.flash.write(address, &self.batch[..len]) {
+            self.lose(batched.bytes as usize, batched.records);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn lose(&mut self, bytes: usize, records: u32) {
+        self.lost.bytes += bytes as u32;
+        self.lost.records += records;
+    }
+
+    /// Erase the oldest sector and make it the head.
+    fn advance(&mut self) -> Result<(), FlashLogError> {
+        let (sector, seq) = match self.head {
+            Some((sector, seq)) => ((sector + 1) % self.sectors, seq.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let address = self.address(sector, 0);
+        // Until the new header is written, the old head is still the newest.
+        self.head = None;
+        self.flash.erase(address, address + Self::SECTOR_SIZE)?;
+        let mut header = [0xff; SECTOR_HEADER_LEN + MAX_WRITE_SIZE];
+        header[..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&seq.to_le_bytes());
+        header[8..12].copy_from_slice(&(!seq).to_le_bytes());
+        self.flash
+            .write(address, &header[..Self::FIRST_RECORD as usize])?;
+        self.head = Some((sector, seq));
+        self.pos = Self::FIRST_RECORD;
+        Ok(())
+    }
+
+    /// Print the log through `usb`, oldest record first.
+    async fn dump<const M: usize>(&mut self, usb: &UsbLogger<M>) {
+        usb.write_console("--- flash log ---\r\n").await;
+        let mut buf = [0; MAX_RECORD_LEN];
+        let mut records = 0;
+        let mut damaged = 0;
+        if let Some((head, head_seq)) = self.head {
+            for i in 1..=self.sectors {
+                let sector = (head + i) % self.sectors;
+                // Sectors left over from an earlier lap are not part of the log.
+                match self.sequence(sector) {
+                    Ok(Some(seq)) if head_seq.wrapping_sub(seq) < self.sectors => {}
+                    _ => continue,
+                }
+                let mut cursor = Cursor {
+                    sector,
+                    pos: Self::FIRST_RECORD,
+                };
+                while let Ok(Entry::Record { len, valid }) = self.entry(cursor, Some(&mut buf)) {
+                    if valid {
+                        let text =
+                            core::str::from_utf8(&buf[..len]).unwrap_or("<invalid utf-8>\r\n");
+                        usb.write_console(text).await;
+                        records += 1;
+                    } else {
+                        damaged += 1;
+                    }
+                    cursor.pos += record_size(len, F::WRITE_SIZE);
+                }
+            }
+        }
+        let mut line = Line::new();
+        let _ = write!(
+            line,
+            "--- end of flash log, {} records, {} damaged ---\r\n",
+            records, damaged
+        );
+        usb.write_console(core::str::from_utf8(line.bytes()).unwrap_or(""))
+            .await;
+    }
+}
+
+/// The most space a record takes.
+const MAX_RECORD_SIZE: usize = align(RECORD_HEADER_LEN + MAX_RECORD_LEN, MAX_WRITE_SIZE);
+
+/// The space a record of `len` bytes takes, padded to the write size.
+fn record_size(len: usize, write_size: usize) -> u32 {
+    align(RECORD_HEADER_LEN + len, write_size) as u32
+}
+
+const fn align(len: usize, to: usize) -> usize {
+    len.div_ceil(to) * to
+}
+
+/// CRC-16/CCITT-FALSE.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+    use std::vec::Vec;
+
+    use embassy_futures::{block_on, yield_now};
+    use embedded_storage::nor_flash::{ErrorType, ReadNorFlash};
+
+    use super::*;
+
+    /// Four 256 byte sectors of NOR flash, where writes can only clear bits.
+    struct RamFlash([u8; 1024]);
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.0[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = 256;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.0[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            for (d, b) in self.0[offset as usize..].iter_mut().zip(bytes) {
+                *d &= b;
+            }
+            Ok(())
+        }
+    }
+
+    fn open(flash: &mut RamFlash) -> FlashLog<&mut RamFlash> {
+        FlashLog::open(flash, 0..1024).unwrap()
+    }
+
+    /// Everything `dump` prints.
+    fn dump(log: &mut FlashLog<&mut RamFlash>) -> String {
+        let usb = UsbLogger::<1024>::new();
+        usb.link.set_lines(true, false);
+        let drain = async {
+            loop {
+                usb.drain();
+                yield_now().await;
+            }
+        };
+        block_on(select(drain, log.dump(&usb)));
+        assert!(usb.drain());
+        let mut out = Vec::new();
+        while let Some(grant) = usb.buffer.read() {
+            out.extend_from_slice(grant.buf());
+            let len = grant.buf().len();
+            grant.release(len);
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn log_is_continued_after_reopening() {
+        let mut flash = RamFlash([0xff; 1024]);
+        let mut log = open(&mut flash);
+        log.append(b"one\r\n").unwrap();
+        log.append(b"two\r\n").unwrap();
+        let mut log = open(&mut flash);
+        log.append(b"three\r\n").unwrap();
+        assert_eq!(
+            dump(&mut log),
+            "--- flash log ---\r\none\r\ntwo\r\nthree\r\n\
+             --- end of flash log, 3 records, 0 damaged ---\r\n"
+        );
+    }
+
+    #[test]
+    fn staged_records_are_written_across_sectors() {
+        let mut flash = RamFlash([0xff; 1024]);
+        let mut log = open(&mut flash);
+        let mut expected = String::from("--- flash log ---\r\n");
+        for i in 0..8 {
+            let record = std::format!("record {} {}\r\n", i, "-".repeat(40));
+            log.stage(record.as_bytes()).unwrap();
+            expected += &record;
+        }
+        assert!(log.batch_len > 0);
+        log.sync().unwrap();
+        assert_eq!(log.lost, DropStats::default());
+        let mut log = open(&mut flash);
+        expected += "--- end of flash log, 8 records, 0 damaged ---\r\n";
+        assert_eq!(dump(&mut log), expected);
+    }
+
+    #[test]
+    fn partly_written_record_is_skipped() {
+        let mut flash = RamFlash([0xff; 1024]);
+        let mut log = open(&mut flash);
+        log.append(b"one\r\n").unwrap();
+        let pos = log.pos as usize;
+        // The power failed after the header and two bytes of "two\r\n".
+        let mut record = [0xff; RECORD_HEADER_LEN + 5];
+        record[..2].copy_from_slice(&5u16.to_le_bytes());
+        record[2..4].copy_from_slice(&crc16(b"two\r\n").to_le_bytes());
+        record[4..6].copy_from_slice(b"tw");
+        flash.write(pos as u32, &record).unwrap();
+        let mut log = open(&mut flash);
+        log.append(b"three\r\n").unwrap();
+        assert_eq!(
+            dump(&mut log),
+            "--- flash log ---\r\none\r\nthree\r\n\
+             --- end of flash log, 2 records, 1 damaged ---\r\n"
+        );
+    }
+
+    #[test]
+    fn damaged_length_moves_on_to_the_next_sector() {
+        let mut flash = RamFlash([0xff; 1024]);
+        let mut log = open(&mut flash);
+        log.append(b"one\r\n").unwrap();
+        let pos = log.pos;
+        // Only the low byte of the length was written.
+        flash.write(pos, &[5]).unwrap();
+        let mut log = open(&mut flash);
+        log.append(b"two\r\n").unwrap();
+        assert_eq!(log.head, Some((1, 1)));
+        assert!(dump(&mut log).contains("one\r\ntwo\r\n"));
+    }
+
+    #[test]
+    fn oldest_sector_is_erased_when_the_log_is_full() {
+        let mut flash = RamFlash([0xff; 1024]);
+        let mut log = open(&mut flash);
+        // 26 bytes each, so nine fit into a sector after its header.
+        for i in 0..40 {
+            log.append(std::format!("record {:02} of the log\r\n", i).as_bytes())
+                .unwrap();
+        }
+        assert_eq!(log.head, Some((0, 4)));
+        let mut log = open(&mut flash);
+        let out = dump(&mut log);
+        let expected: String = (9..40)
+            .map(|i| std::format!("record {:02} of the log\r\n", i))
+            .collect();
+        assert!(out.contains(&expected));
+        assert!(out.ends_with("31 records, 0 damaged ---\r\n"));
+    }
+}
@@ -0,0 +1,110 @@
+//! Built-in record formats.
+
+use core::fmt::{self, Write};
+
+use embassy_time::Instant;
+use log::Record;
+
+use crate::{current_core, json};
+
+/// A built-in layout for log records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Style {
+    /// Only the message: `portb = 00000000`.
+    Plain,
+    /// Uptime, core and level: `[12.345 c0 INFO ] portb = 00000000`.
+    #[default]
+    Compact,
+    /// Uptime, core, level and source location:
+    /// `[12.345 c0 INFO  main:118] portb = 00000000`.
+    Verbose,
+    /// One JSON object per line, with the uptime in milliseconds, the source
+    /// location and any key-value pairs of the record before the message:
+    /// `{"ts":12345,"level":"INFO","target":"main","file":"src/main.rs",
+    /// "line":118,"port":"b","msg":"portb = 00000000"}`.
+    Json,
+}
+
+impl Style {
+    /// Write a record in this style, terminated by `\r\n`.
+    pub fn write(self, record: &Record, out: &mut dyn Write) -> fmt::Result {
+        match self {
+            Style::Plain => write!(out, "{}\r\n", record.args()),
+            Style::Compact => {
+                write_uptime(out)?;
+                write!(out, " {:<5}] {}\r\n", record.level(), record.args())
+            }
+            Style::Verbose => {
+                write_uptime(out)?;
+                write!(
+                    out,
+                    " {:<5} {}:{}] {}\r\n",
+                    record.level(),
+                    record.module_path().unwrap_or(record.target()),
+                    record.line().unwrap_or(0),
+                    record.args()
+                )
+            }
+            Style::Json => json::write(record, out),
+        }
+    }
+}
+
+/// Write `[seconds.millis` of time since boot and the current core.
+fn write_uptime(out: &mut dyn Write) -> fmt::Result {
+    let ms = Instant::now().as_millis();
+    write!(out, "[{}.{:03} c{}", ms / 1000, ms % 1000, current_core())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use log::{Level, Record};
+
+    use super::*;
+
+    fn format(style: Style, level: Level) -> String {
+        let mut out = String::new();
+        style
+            .write(
+                &Record::builder()
+                    .args(format_args!("portb = {:08b}", 5))
+                    .level(level)
+                    .target("main")
+                    .module_path(Some("main"))
+                    .line(Some(118))
+                    .build(),
+                &mut out,
+            )
+            .unwrap();
+        out
+    }
+
+    /// Split `[12.345 rest` into the uptime and the rest.
+    fn split_uptime(line: &str) -> (&str, &str) {
+        line.strip_prefix('[').unwrap().split_once(' ').unwrap()
+    }
+
+    #[test]
+    fn plain_is_only_the_message() {
+        assert_eq!(format(Style::Plain, Level::Info), "portb = 00000101\r\n");
+    }
+
+    #[test]
+    fn compact_has_uptime_core_and_level() {
+        let line = format(Style::Compact, Level::Info);
+        let (uptime, rest) = split_uptime(&line);
+        let (secs, millis) = uptime.split_once('.').unwrap();
+        assert!(secs.parse::<u64>().is_ok());
+        assert_eq!(millis.len(), 3);
+        assert_eq!(rest, "c0 INFO ] portb = 00000101\r\n");
+    }
+
+    #[test]
+    fn verbose_has_source_location() {
+        let line = format(Style::Verbose, Level::Warn);
+        let (_, rest) = split_uptime(&line);
+        assert_eq!(rest, "c0 WARN  main:118] portb = 00000101\r\n");
+    }
+}
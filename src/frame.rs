@@ -0,0 +1,134 @@
+//! COBS-framed binary encoding of log records.
+//!
+//! Each record is sent as a single frame with the following layout, before
+//! COBS encoding:
+//!
+//! | field       | size       | notes                                        |
+//! |-------------|------------|----------------------------------------------|
+//! | version     | 1          | currently `2`                                |
+//! | sequence    | 4, LE      | incremented for every frame, gaps mean drops |
+//! | timestamp   | 8, LE      | microseconds since boot                      |
+//! | level       | 1          | `0` console output, `1` error .. `5` trace   |
+//! | core        | 1          | the core the record was logged on            |
+//! | target len  | 1          |                                              |
+//! | target      | target len | UTF-8                                        |
+//! | message     | remainder  | UTF-8                                        |
+//!
+//! The encoded frame is terminated by a single zero byte. The host side
+//! decoder lives in the `debug` crate.
+
+use core::fmt::{self, Write};
+
+use embassy_time::Instant;
+
+use crate::{current_core, MAX_RECORD_LEN};
+
+/// The version byte at the start of every frame.
+pub const VERSION: u8 = 2;
+
+/// The level byte used for console output, which has no `log::Level`.
+pub const LEVEL_CONSOLE: u8 = 0;
+
+/// The size of the fixed header in front of the target.
+const HEADER_LEN: usize = 16;
+
+/// The largest frame that still fits into `MAX_RECORD_LEN` once COBS encoded
+/// and terminated.
+const MAX_FRAME_LEN: usize = MAX_RECORD_LEN - 2 - MAX_RECORD_LEN / 254;
+
+/// How records are written to the USB logger buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Human readable lines, laid out by the logger's style.
+    #[default]
+    Text,
+    /// COBS framed binary records, see the module documentation.
+    Cobs,
+    /// defmt frames, which need the `defmt-usb` feature and the firmware's
+    /// ELF file to decode. See the `defmt_usb` module.
+    Defmt,
+}
+
+impl Encoding {
+    /// The byte every record ends in.
+    pub(crate) const fn delimiter(self) -> u8 {
+        match self {
+            Self::Text => b'\n',
+            Self::Cobs | Self::Defmt => 0,
+        }
+    }
+}
+
+/// Encode a frame into `dst`, returning the number of bytes written including
+/// the terminating zero. The target and message are truncated to fit.
+pub fn encode(
+    dst: &mut [u8; MAX_RECORD_LEN],
+    seq: u32,
+    level: u8,
+    target: &str,
+    args: fmt::Arguments,
+) -> usize {
+    let mut raw = SliceWriter {
+        buf: [0; MAX_FRAME_LEN],
+        len: HEADER_LEN,
+    };
+    raw.buf[0] = VERSION;
+    raw.buf[1..5].copy_from_slice(&seq.to_le_bytes());
+    raw.buf[5..13].copy_from_slice(&Instant::now().as_micros().to_le_bytes());
+    raw.buf[13] = level;
+    raw.buf[14] = current_core();
+    let _ = raw.write_str(&target[..floor_char_boundary(target, u8::MAX as usize)]);
+    raw.buf[15] = (raw.len - HEADER_LEN) as u8;
+    let _ = raw.write_fmt(args);
+
+    let len = cobs_encode(&raw.buf[..raw.len], dst);
+    dst[len] = 0;
+    len + 1
+}
+
+/// COBS encode `src` into `dst`, which must hold at least
+/// `src.len() + src.len() / 254 + 1` bytes.
+fn cobs_encode(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut code_idx = 0;
+    let mut out = 1;
+    let mut code = 1u8;
+    for &b in src {
+        if b != 0 {
+            dst[out] = b;
+            out += 1;
+            code += 1;
+        }
+        if b == 0 || code == 0xff {
+            dst[code_idx] = code;
+            code_idx = out;
+            out += 1;
+            code = 1;
+        }
+    }
+    dst[code_idx] = code;
+    out
+}
+
+/// The largest index not above `max` that is on a character boundary of `s`.
+fn floor_char_boundary(s: &str, max: usize) -> usize {
+    let mut i = max.min(s.len());
+    while !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+/// Formats into a fixed buffer, silently truncating at a character boundary.
+struct SliceWriter {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl Write for SliceWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = floor_char_boundary(s, MAX_FRAME_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+//! A byte queue handing out contiguous grants, in the style of bbqueue.
+//!
+//! The producer reserves contiguous space with `grant`, writes into it in place
+//! and commits the part it used. The consumer gets the oldest contiguous
+//! readable bytes with `read`, sends them straight from the queue and releases
+//! what was sent. A grant that does not fit before the end of the queue starts
+//! at the front instead, and the unused end is skipped when reading, so no
+//! record is ever split around the wraparound.
+
+use core::cell::{Cell, UnsafeCell};
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+
+use crate::CS;
+
+#[derive(Clone, Copy)]
+struct State {
+    /// The position of the oldest readable byte.
+    read: usize,
+    /// The position after the newest readable byte.
+    write: usize,
+    /// The end of the readable bytes before `read` wrapped, while `inverted`.
+    last: usize,
+    /// Whether `write` has wrapped to the front and `read` has not yet.
+    inverted: bool,
+    /// The start of the outstanding write grant.
+    reserved: Option<usize>,
+    /// The length of the outstanding read grant, zero if there is none.
+    granted: usize,
+}
+
+impl State {
+    /// Where a grant of `len` bytes would start.
+    fn grant_start(&self, len: usize, size: usize) -> Option<usize> {
+        if self.inverted {
+            (self.write + len <= self.read).then_some(self.write)
+        } else if self.write + len <= size {
+            Some(self.write)
+        } else {
+            (len <= self.read).then_some(0)
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.inverted && self.read == self.write
+    }
+
+    /// Move `read` to the front once the end has been read, and start over
+    /// from the front once everything has been.
+    fn normalize(&mut self) {
+        if self.inverted && self.read == self.last {
+            self.read = 0;
+            self.inverted = false;
+        }
+        if self.is_empty() && self.reserved.is_none() && self.granted == 0 {
+            self.read = 0;
+            self.write = 0;
+        }
+    }
+}
+
+/// A queue of `N` bytes with one producer and one consumer at a time.
+pub(crate) struct GrantQueue<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    state: Mutex<CS, Cell<State>>,
+    committed: Signal<CS, ()>,
+}
+
+// Safety: a write grant only covers bytes that are not readable, a read grant
+// only readable ones, and there is at most one of each.
+unsafe impl<const N: usize> Sync for GrantQueue<N> {}
+
+impl<const N: usize> GrantQueue<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; N]),
+            state: Mutex::new(Cell::new(State {
+                read: 0,
+                write: 0,
+                last: 0,
+                inverted: false,
+                reserved: None,
+                granted: 0,
+            })),
+            committed: Signal::new(),
+        }
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        self.buf.get().cast()
+    }
+
+    fn update<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        self.state.lock(|state| {
+            let mut s = state.get();
+            let r = f(&mut s);
+            state.set(s);
+            r
+        })
+    }
+
+    /// Reserve `len` contiguous bytes, or return `None` if they do not fit or
+    /// a write grant is outstanding.
+    pub(crate) fn grant(&self, len: usize) -> Option<WriteGrant<'_, N>> {
+        let start = self.update(|s| {
+            if s.reserved.is_some() {
+                return None;
+            }
+            s.normalize();
+            let start = s.grant_start(len, N)?;
+            s.reserved = Some(start);
+            Some(start)
+        })?;
+        Some(WriteGrant {
+            queue: self,
+            start,
+            len,
+        })
+    }
+
+    /// Returns whether a grant of `len` bytes would fit.
+    pub(crate) fn fits(&self, len: usize) -> bool {
+        self.update(|s| {
+            s.normalize();
+            s.grant_start(len, N).is_some()
+        })
+    }
+
+    fn commit(&self, start: usize, used: usize) {
+        self.update(|s| {
+            // An unused grant leaves `write` where it was, even if it wrapped.
+            if used > 0 {
+                if start != s.write {
+                    // The grant wrapped to the front.
+                    s.last = s.write;
+                    s.inverted = true;
+                }
+                s.write = start + used;
+            }
+            s.reserved = None;
+            s.normalize();
+        });
+        if used > 0 {
+            self.committed.signal(());
+        }
+    }
+
+    /// Returns the oldest contiguous readable bytes, or `None` if there are
+    /// none or a read grant is outstanding.
+    pub(crate) fn read(&self) -> Option<ReadGrant<'_, N>> {
+        self.read_max(N)
+    }
+
+    /// Returns at most `max` of the oldest contiguous readable bytes, or
+    /// `None` if there are none or a read grant is outstanding.
+    pub(crate) fn read_max(&self, max: usize) -> Option<ReadGrant<'_, N>> {
+        let (start, len) = self.update(|s| {
+            if s.granted > 0 {
+                return None;
+            }
+            s.normalize();
+            let end = if s.inverted { s.last } else { s.write };
+            s.granted = (end - s.read).min(max);
+            (s.granted > 0).then_some((s.read, s.granted))
+        })?;
+        Some(ReadGrant {
+            queue: self,
+            start,
+            len,
+        })
+    }
+
+    fn release(&self, used: usize) {
+        self.update(|s| {
+            s.read += used;
+            s.granted = 0;
+            s.normalize();
+        });
+    }
+
+    /// Wait until there are bytes to read.
+    pub(crate) async fn wait(&self) {
+        while self.is_empty() {
+            self.committed.wait().await;
+        }
+    }
+
+    /// Returns whether a read grant is outstanding.
+    pub(crate) fn is_reading(&self) -> bool {
+        self.update(|s| s.granted > 0)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.update(|s| s.is_empty())
+    }
+
+    /// Discard everything. Must not be called while a grant is outstanding.
+    pub(crate) fn clear(&self) {
+        self.update(|s| {
+            debug_assert!(s.reserved.is_none() && s.granted == 0);
+            s.read = 0;
+            s.write = 0;
+            s.inverted = false;
+        });
+    }
+}
+
+/// Contiguous space reserved for writing. Dropping it commits nothing.
+pub(crate) struct WriteGrant<'a, const N: usize> {
+    queue: &'a GrantQueue<N>,
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> WriteGrant<'_, N> {
+    pub(crate) fn buf(&mut self) -> &mut [u8] {
+        // Safety: the reserved bytes are not readable and there is no other
+        // write grant.
+        unsafe { core::slice::from_raw_parts_mut(self.queue.ptr().add(self.start), self.len) }
+    }
+
+    /// Make the first `used` bytes readable.
+    pub(crate) fn commit(self, used: usize) {
+        self.queue.commit(self.start, used.min(self.len));
+        core::mem::forget(self);
+    }
+}
+
+impl<const N: usize> Drop for WriteGrant<'_, N> {
+    fn drop(&mut self) {
+        self.queue.commit(self.start, 0);
+    }
+}
+
+/// The oldest contiguous readable bytes. Dropping it releases nothing.
+pub(crate) struct ReadGrant<'a, const N: usize> {
+    queue: &'a GrantQueue<N>,
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> ReadGrant<'_, N> {
+    pub(crate) fn buf(&self) -> &[u8] {
+        // Safety: the granted bytes are readable, so no write grant covers them.
+        unsafe { core::slice::from_raw_parts(self.queue.ptr().add(self.start), self.len) }
+    }
+
+    /// Remove the first `used` bytes from the queue.
+    pub(crate) fn release(self, used: usize) {
+        self.queue.release(used.min(self.len));
+        core::mem::forget(self);
+    }
+}
+
+impl<const N: usize> Drop for ReadGrant<'_, N> {
+    fn drop(&mut self) {
+        self.queue.release(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write<const N: usize>(queue: &GrantQueue<N>, b: &[u8]) -> bool {
+        let Some(mut grant) = queue.grant(b.len()) else {
+            return false;
+        };
+        grant.buf().copy_from_slice(b);
+        grant.commit(b.len());
+        true
+    }
+
+    fn read<const N: usize>(queue: &GrantQueue<N>, len: usize) -> std::vec::Vec<u8> {
+        let grant = queue.read().unwrap();
+        let data = grant.buf()[..len].to_vec();
+        grant.release(len);
+        data
+    }
+
+    #[test]
+    fn unused_end_is_skipped() {
+        let queue = GrantQueue::<8>::new();
+        assert!(write(&queue, b"abcdef"));
+        assert_eq!(read(&queue, 4), b"abcd");
+        assert!(write(&queue, b"xyz"));
+        assert_eq!(queue.read().unwrap().buf(), b"ef");
+        assert_eq!(read(&queue, 2), b"ef");
+        assert_eq!(read(&queue, 3), b"xyz");
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn wrapped_queue_fills_up_to_the_oldest_byte() {
+        let queue = GrantQueue::<8>::new();
+        assert!(write(&queue, b"abcdef"));
+        assert_eq!(read(&queue, 4), b"abcd");
+        assert!(write(&queue, b"wxyz"));
+        assert!(!queue.fits(1));
+    }
+
+    #[test]
+    fn empty_queue_starts_over_from_the_front() {
+        let queue = GrantQueue::<8>::new();
+        assert!(write(&queue, b"abcdef"));
+        assert_eq!(read(&queue, 6), b"abcdef");
+        assert!(write(&queue, b"01234567"));
+    }
+
+    #[test]
+    fn dropped_grants_change_nothing() {
+        let queue = GrantQueue::<8>::new();
+        drop(queue.grant(4));
+        assert!(queue.is_empty());
+        assert!(write(&queue, b"abc"));
+        drop(queue.read());
+        assert_eq!(read(&queue, 3), b"abc");
+        // An unused grant that wrapped to the front leaves the end in use.
+        assert!(write(&queue, b"abcdef"));
+        assert_eq!(read(&queue, 4), b"abcd");
+        drop(queue.grant(4));
+        assert!(write(&queue, b"gh"));
+        assert_eq!(queue.read().unwrap().buf(), b"efgh");
+    }
+
+    #[test]
+    fn read_grant_is_limited_to_max() {
+        let queue = GrantQueue::<8>::new();
+        assert!(write(&queue, b"abcdef"));
+        let grant = queue.read_max(4).unwrap();
+        assert_eq!(grant.buf(), b"abcd");
+        grant.release(4);
+        assert_eq!(read(&queue, 2), b"ef");
+    }
+}
@@ -0,0 +1,55 @@
+//! A ring of the most recent log output, replayed to every new host session.
+
+/// The last `N` bytes written to the logger, addressed by their position in
+/// the whole output stream since boot.
+pub(crate) struct History<const N: usize> {
+    buf: [u8; N],
+    /// The number of bytes ever written.
+    end: u64,
+}
+
+impl<const N: usize> History<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            end: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        // Only the tail of output longer than the history is kept.
+        let skipped = bytes.len().saturating_sub(N);
+        self.end += skipped as u64;
+        let bytes = &bytes[skipped..];
+        let start = (self.end % N as u64) as usize;
+        let first = bytes.len().min(N - start);
+        self.buf[start..start + first].copy_from_slice(&bytes[..first]);
+        self.buf[..bytes.len() - first].copy_from_slice(&bytes[first..]);
+        self.end += bytes.len() as u64;
+    }
+
+    /// The position after the newest byte.
+    pub(crate) fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// The position of the oldest byte still held.
+    pub(crate) fn start(&self) -> u64 {
+        self.end.saturating_sub(N as u64)
+    }
+
+    /// Copy bytes from position `pos` into `dst`, stopping at `until`, and
+    /// return the position of the first byte copied and the number copied.
+    ///
+    /// Bytes that have been overwritten since are skipped, so the returned
+    /// position can be later than `pos`.
+    pub(crate) fn read(&self, pos: u64, until: u64, dst: &mut [u8]) -> (u64, usize) {
+        let pos = pos.max(self.start());
+        let len = (until.saturating_sub(pos) as usize).min(dst.len());
+        let start = (pos % N as u64) as usize;
+        let first = len.min(N - start);
+        dst[..first].copy_from_slice(&self.buf[start..start + first]);
+        dst[first..len].copy_from_slice(&self.buf[..len - first]);
+        (pos, len)
+    }
+}
@@ -0,0 +1,116 @@
+//! Installing a `UsbLogger` as the global logger.
+//!
+//! The logger is configured with its const builders and kept in a `static`.
+//! The buffer size is its type parameter. `install` makes it the global logger
+//! once and returns a handle, which picks the transport the output is sent
+//! over:
+//!
+//! ```
+//! static LOGGER: UsbLogger<1024> = UsbLogger::new()
+//!     .with_level(LevelFilter::Info)
+//!     .with_style(Style::Verbose);
+//!
+//! let config = LoggerConfig::from_flash(&mut flash);
+//! let logger = LOGGER.install().unwrap();
+//! logger.run(&mut LoggerState::new(), driver, config).await;
+//! ```
+
+use core::fmt;
+use core::ops::Deref;
+
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::driver::Driver;
+
+use crate::config::LoggerConfig;
+use crate::{sink, LoggerState, UsbLogger};
+
+/// An error returned by `UsbLogger::install`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitError {
+    /// A global logger was installed before, by this or any other means.
+    AlreadyInitialized,
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyInitialized => f.write_str("a global logger is already installed"),
+        }
+    }
+}
+
+impl<const N: usize> UsbLogger<N> {
+    /// Make this logger the global logger and set the global maximum level to
+    /// that of its filter.
+    ///
+    /// Only the first logger installed in the program is used, so this fails
+    /// if there already is one, including the fan-out logger of `sink`.
+    pub fn install(&'static self) -> Result<LoggerHandle<N>, InitError> {
+        // Safety: the critical section keeps other cores and interrupts from
+        // setting a logger at the same time.
+        critical_section::with(|_| unsafe { log::set_logger_racy(self) })
+            .map_err(|_| InitError::AlreadyInitialized)?;
+        sink::update_max_level(self.filter().max_level());
+        Ok(LoggerHandle { logger: self })
+    }
+}
+
+/// An installed `UsbLogger`, returned by `UsbLogger::install`.
+///
+/// It dereferences to the logger, so the filter and statistics can be reached
+/// through it as well.
+#[derive(Clone, Copy)]
+pub struct LoggerHandle<const N: usize> {
+    logger: &'static UsbLogger<N>,
+}
+
+impl<const N: usize> LoggerHandle<N> {
+    /// Send the output over a USB device of its own, built from the driver and
+    /// device configuration. Never returns.
+    pub async fn run<'d, D>(
+        self,
+        state: &'d mut LoggerState<'d>,
+        driver: D,
+        config: LoggerConfig<'d>,
+    ) -> !
+    where
+        D: Driver<'d>,
+    {
+        self.logger.run(state, driver, config).await
+    }
+
+    /// Send the output over a serial class of a USB device run by the caller.
+    pub async fn run_with_class<'d, D>(self, class: CdcAcmClass<'d, D>)
+    where
+        D: Driver<'d>,
+    {
+        self.logger.create_future_from_class(class).await
+    }
+}
+
+impl<const N: usize> Deref for LoggerHandle<N> {
+    type Target = UsbLogger<N>;
+
+    fn deref(&self) -> &UsbLogger<N> {
+        self.logger
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LevelFilter;
+
+    use super::*;
+
+    static FIRST: UsbLogger<64> = UsbLogger::new().with_level(LevelFilter::Warn);
+    static SECOND: UsbLogger<64> = UsbLogger::new();
+
+    #[test]
+    fn only_the_first_logger_is_installed() {
+        let handle = FIRST.install().unwrap();
+        assert_eq!(handle.filter().max_level(), LevelFilter::Warn);
+        assert_eq!(log::max_level(), LevelFilter::Warn);
+        assert_eq!(SECOND.install().err(), Some(InitError::AlreadyInitialized));
+        assert_eq!(FIRST.install().err(), Some(InitError::AlreadyInitialized));
+    }
+}
@@ -0,0 +1,270 @@
+//! The JSON Lines layout of `Style::Json`.
+//!
+//! Every record is one JSON object on one line. The whole line, including the
+//! `\r\n`, is kept within `MAX_RECORD_LEN` so it is never cut in the middle of
+//! the object: long strings are shortened to end in `...` instead, and
+//! key-value pairs that do not fit are left out.
+
+use core::fmt::{self, Write};
+
+use embassy_time::Instant;
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+
+use crate::MAX_RECORD_LEN;
+
+/// Ends the object and the line.
+const END: &str = "}\r\n";
+
+/// Ends a string that was shortened to fit.
+const TRUNCATED: &str = "...";
+
+/// The longest target or file name written, between the quotes.
+const MAX_NAME_LEN: usize = 64;
+
+/// The room kept for the message while the key-value pairs are written,
+/// enough for `,"msg":"..."` and `END`.
+const MSG_RESERVE: usize = ",\"msg\":\"\"".len() + TRUNCATED.len() + END.len();
+
+/// Write `record` as a JSON object, terminated by `\r\n`.
+pub(crate) fn write(record: &Record, out: &mut dyn Write) -> fmt::Result {
+    let mut json = Json { out, len: 0 };
+    write!(
+        json,
+        "{{\"ts\":{},\"level\":\"{}\",\"target\":",
+        Instant::now().as_millis(),
+        record.level()
+    )?;
+    json.string(format_args!("{}", record.target()), MAX_NAME_LEN)?;
+    json.write_str(",\"file\":")?;
+    match record.file() {
+        Some(file) => json.string(format_args!("{}", file), MAX_NAME_LEN)?,
+        None => json.write_str("null")?,
+    }
+    match record.line() {
+        Some(line) => write!(json, ",\"line\":{}", line)?,
+        None => json.write_str(",\"line\":null")?,
+    }
+    let _ = record.key_values().visit(&mut Fields(&mut json));
+    json.write_str(",\"msg\":")?;
+    let max_len = MAX_RECORD_LEN - json.len - 2 - END.len();
+    json.string(*record.args(), max_len)?;
+    json.write_str(END)
+}
+
+/// Passes everything written on, keeping count of the line length.
+struct Json<'a> {
+    out: &'a mut dyn Write,
+    len: usize,
+}
+
+impl Json<'_> {
+    /// Write `args` as a JSON string of at most `max_len` bytes between the
+    /// quotes, ending in `...` if it had to be shortened.
+    fn string(&mut self, args: fmt::Arguments, max_len: usize) -> fmt::Result {
+        self.write_char('"')?;
+        let mut escaped = Escaped {
+            out: &mut *self,
+            room: max_len - TRUNCATED.len(),
+            cut: false,
+        };
+        let result = escaped.write_fmt(args);
+        if escaped.cut {
+            self.write_str(TRUNCATED)?;
+        } else {
+            result?;
+        }
+        self.write_char('"')
+    }
+
+    /// Write a key-value pair, unless it would leave no room for the message.
+    fn field(&mut self, key: &Key, value: &Value) -> fmt::Result {
+        let mut len = Count(0);
+        write_field(&mut len, key, value)?;
+        if self.len + len.0 + MSG_RESERVE > MAX_RECORD_LEN {
+            return Ok(());
+        }
+        write_field(self, key, value)
+    }
+}
+
+impl Write for Json<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.len += s.len();
+        self.out.write_str(s)
+    }
+}
+
+/// Writes the key-value pairs of a record.
+struct Fields<'a, 'b>(&'a mut Json<'b>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_, '_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.field(&key, &value)?;
+        Ok(())
+    }
+}
+
+/// Write `,"key":value`, with numbers and booleans as they are and everything
+/// else as a string.
+fn write_field(out: &mut dyn Write, key: &Key, value: &Value) -> fmt::Result {
+    out.write_char(',')?;
+    write_string(out, format_args!("{}", key))?;
+    out.write_char(':')?;
+    if let Some(b) = value.to_bool() {
+        write!(out, "{}", b)
+    } else if let Some(n) = value.to_u64() {
+        write!(out, "{}", n)
+    } else if let Some(n) = value.to_i64() {
+        write!(out, "{}", n)
+    } else if let Some(f) = value.to_f64().filter(|f| f.is_finite()) {
+        write!(out, "{}", f)
+    } else {
+        write_string(out, format_args!("{}", value))
+    }
+}
+
+fn write_string(out: &mut dyn Write, args: fmt::Arguments) -> fmt::Result {
+    out.write_char('"')?;
+    Escaped {
+        out,
+        room: usize::MAX,
+        cut: false,
+    }
+    .write_fmt(args)?;
+    out.write_char('"')
+}
+
+/// Escapes what is written for use in a JSON string, and stops once `room`
+/// bytes have been written.
+struct Escaped<'a> {
+    out: &'a mut dyn Write,
+    room: usize,
+    /// Whether something did not fit.
+    cut: bool,
+}
+
+impl Write for Escaped<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut buf = [0; 6];
+            let escaped = escape(c, &mut buf);
+            if escaped.len() > self.room {
+                self.cut = true;
+                return Err(fmt::Error);
+            }
+            self.out.write_str(escaped)?;
+            self.room -= escaped.len();
+        }
+        Ok(())
+    }
+}
+
+fn escape(c: char, buf: &mut [u8; 6]) -> &str {
+    match c {
+        '"' => "\\\"",
+        '\\' => "\\\\",
+        '\n' => "\\n",
+        '\r' => "\\r",
+        '\t' => "\\t",
+        c if (c as u32) < 0x20 => {
+            const HEX: &[u8; 16] = b"0123456789abcdef";
+            *buf = *b"\\u0000";
+            buf[4] = HEX[c as usize >> 4];
+            buf[5] = HEX[c as usize & 0xf];
+            // The buffer is all ASCII.
+            core::str::from_utf8(buf).unwrap_or_default()
+        }
+        c => c.encode_utf8(buf),
+    }
+}
+
+/// Counts the bytes written to it.
+struct Count(usize);
+
+impl Write for Count {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use log::Level;
+
+    use super::*;
+
+    /// Format a record and drop the `ts` field, which depends on the uptime.
+    fn format(record: &Record) -> String {
+        let mut out = String::new();
+        write(record, &mut out).unwrap();
+        let (ts, rest) = out.split_once(',').unwrap();
+        assert!(ts.strip_prefix("{\"ts\":").unwrap().parse::<u64>().is_ok());
+        std::format!("{{{}", rest)
+    }
+
+    #[test]
+    fn record_is_one_object_with_its_fields() {
+        let fields = [
+            ("port", Value::from("b")),
+            ("count", Value::from(3u8)),
+            ("delta", Value::from(-2i32)),
+            ("ok", Value::from(true)),
+        ];
+        let line = format(
+            &Record::builder()
+                .args(format_args!("portb = {:08b}", 5))
+                .level(Level::Info)
+                .target("main")
+                .file(Some("src/main.rs"))
+                .line(Some(118))
+                .key_values(&fields)
+                .build(),
+        );
+        assert_eq!(
+            line,
+            "{\"level\":\"INFO\",\"target\":\"main\",\"file\":\"src/main.rs\",\"line\":118,\
+             \"port\":\"b\",\"count\":3,\"delta\":-2,\"ok\":true,\"msg\":\"portb = 00000101\"}\r\n"
+        );
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let line = format(
+            &Record::builder()
+                .args(format_args!("\"a\\b\"\n\t\u{1}é"))
+                .level(Level::Warn)
+                .target("main")
+                .build(),
+        );
+        assert_eq!(
+            line,
+            "{\"level\":\"WARN\",\"target\":\"main\",\"file\":null,\"line\":null,\
+             \"msg\":\"\\\"a\\\\b\\\"\\n\\t\\u0001é\"}\r\n"
+        );
+    }
+
+    #[test]
+    fn long_record_stays_one_object() {
+        let message = "\"".repeat(300);
+        let fields = [
+            ("text", Value::from(message.as_str())),
+            ("n", Value::from(1u8)),
+        ];
+        let line = format(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Info)
+                .target("main")
+                .key_values(&fields)
+                .build(),
+        );
+        assert!(line.len() < MAX_RECORD_LEN);
+        assert!(!line.contains("\"text\""));
+        assert!(line.contains(",\"n\":1,"));
+        assert!(line.ends_with("\\\"...\"}\r\n"));
+    }
+}
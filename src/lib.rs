@@ -4,53 +4,148 @@ https://github.com/embassy-rs/embassy
 
 */
 
-#![no_std]
-
+#![cfg_attr(not(test), no_std)]
+
+pub mod config;
+pub mod console;
+pub mod crashlog;
+mod dedup;
+#[cfg(feature = "defmt-usb")]
+pub mod defmt_usb;
+pub mod device;
+pub mod filter;
+pub mod flashlog;
+pub mod format;
+pub mod frame;
+mod grant;
+mod history;
+pub mod init;
+mod json;
+mod line;
+pub mod link;
+pub mod overflow;
+mod packets;
+#[cfg(target_os = "none")]
+pub mod panic;
+pub mod ratelimit;
+pub mod sink;
+mod staging;
+pub mod syslog;
+
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
+
+use core::cell::RefCell;
 use core::fmt::Write as _;
 
-use embassy_futures::join::join;
-use embassy_sync::pipe::Pipe;
-use embassy_usb::class::cdc_acm::{CdcAcmClass, Receiver, Sender, State};
+use embassy_futures::join::join3;
+use embassy_futures::select::{select, select3, Either3};
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, ControlChanged, Receiver, Sender};
 use embassy_usb::driver::Driver;
-use embassy_usb::{Builder, Config};
-use log::{Metadata, Record};
+use log::{LevelFilter, Metadata, Record};
+use portable_atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+use crate::config::LoggerConfig;
+use crate::console::{split_command, Console, COMMANDS};
+use crate::dedup::{Check, Repeated, Repeats};
+use crate::device::{DeviceBuilder, DeviceState};
+use crate::filter::{Filter, FilterError};
+use crate::format::Style;
+use crate::frame::Encoding;
+use crate::grant::GrantQueue;
+use crate::history::History;
+use crate::line::Line;
+use crate::link::{Link, LinkState};
+use crate::overflow::{DropCounters, DropStats, OverflowPolicy};
+use crate::packets::{ControlLines, Detached, Packets, Port};
+use crate::sink::RttSink;
+use crate::staging::CoreStaging;
 type CS = embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 
-/// The logger state containing buffers that must live as long as the USB peripheral.
-pub struct LoggerState<'d> {
-    state: State<'d>,
-    config_descriptor: [u8; 128],
-    bos_descriptor: [u8; 16],
-    msos_descriptor: [u8; 256],
-    control_buf: [u8; 64],
-}
+/// The device state used by `UsbLogger::run`, with buffers that fit the
+/// logger's serial port and whatever else the device has room for.
+pub type LoggerState<'d> = DeviceState<'d>;
 
-impl<'d> Default for LoggerState<'d> {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+/// The packet size used in the usb logger, to be used with `create_future_from_class`
+pub const MAX_PACKET_SIZE: u8 = 64;
 
-impl LoggerState<'_> {
-    /// Create a new instance of the logger state.
-    pub fn new() -> Self {
-        Self {
-            state: State::new(),
-            config_descriptor: [0; 128],
-            bos_descriptor: [0; 16],
-            msos_descriptor: [0; 256],
-            control_buf: [0; 64],
-        }
-    }
+/// The maximum length of a single formatted record.
+///
+/// Longer records are cut at a character boundary and end in `...\r\n`.
+pub const MAX_RECORD_LEN: usize = 256;
+
+const PREVIOUS_BOOT_PREFIX: &[u8] = b"previous boot | ";
+
+/// How long `log::Log::flush` waits for the buffer to drain.
+const FLUSH_TIMEOUT_MS: u64 = 100;
+
+/// How often the USB task looks for records staged by interrupt handlers.
+const DRAIN_POLL_MS: u64 = 10;
+
+/// Returns the number of the core this is running on, `0` or `1`.
+#[cfg(target_os = "none")]
+pub fn current_core() -> u8 {
+    embassy_rp::pac::SIO.cpuid().read() as u8
 }
 
-/// The packet size used in the usb logger, to be used with `create_future_from_class`
-pub const MAX_PACKET_SIZE: u8 = 64;
+/// Returns `0`, as host builds only run the logic that does not touch the chip.
+#[cfg(not(target_os = "none"))]
+pub fn current_core() -> u8 {
+    0
+}
 
-/// The logger handle, which contains a pipe with configurable size for buffering log messages.
+/// The logger handle, which contains a buffer with configurable size for log messages.
+///
+/// The last `N` bytes of output are also kept in a history, which is sent
+/// again with a session banner every time a terminal opens the port.
+///
+/// Records can be logged from both cores and from interrupt handlers. Each core
+/// has a small staging ring for thread mode and one per interrupt priority,
+/// which also counts the records that did not fit into it. Records are checked
+/// against the most verbose level of the filter without a lock, so only a
+/// filter with per-target levels, the repeat suppression, the list of `sink`
+/// loggers and the sequence number of `Encoding::Cobs` frames take short
+/// critical sections. The USB task moves the records into the buffer in the
+/// order they were logged, where the overflow policy applies, and sends them
+/// straight from there.
 pub struct UsbLogger<const N: usize> {
-    buffer: Pipe<CS, N>,
+    buffer: GrantQueue<N>,
     custom_style: Option<fn(&Record, &mut Writer<'_, N>) -> ()>,
+    style: Style,
+    filter: Mutex<CS, RefCell<Filter>>,
+    /// The `max_level` of the filter, so most records are rejected without
+    /// taking its lock.
+    max_level: AtomicU8,
+    /// Whether the filter has no per-target levels, so `max_level` decides
+    /// alone.
+    uniform: AtomicBool,
+    policy: OverflowPolicy,
+    drops: DropCounters,
+    encoding: Encoding,
+    seq: AtomicU32,
+    history: Mutex<CS, RefCell<History<N>>>,
+    sessions: AtomicU32,
+    link: Link,
+    /// The number of records dropped when the last session ended.
+    dropped_at_detach: AtomicU32,
+    /// Records committed on each core, waiting to be moved into `buffer`.
+    staging: [CoreStaging; 2],
+    staged: Signal<CS, ()>,
+    /// Whether the replays are still being sent.
+    sending: AtomicBool,
+    sent: Signal<CS, ()>,
+    /// Whether `flush` waits for the host to confirm it read the output.
+    confirm: AtomicBool,
+    flushing: Signal<CS, ()>,
+    /// How long repeats of a record may be collapsed before they are reported.
+    dedup: Option<Duration>,
+    repeats: Mutex<CS, RefCell<Repeats>>,
 }
 
 impl<const N: usize> Default for UsbLogger<N> {
@@ -63,135 +158,830 @@ impl<const N: usize> UsbLogger<N> {
     /// Create a new logger instance.
     pub const fn new() -> Self {
         Self {
-            buffer: Pipe::new(),
+            buffer: GrantQueue::new(),
             custom_style: None,
+            style: Style::Compact,
+            filter: Mutex::new(RefCell::new(Filter::new(LevelFilter::Trace))),
+            max_level: AtomicU8::new(LevelFilter::Trace as u8),
+            uniform: AtomicBool::new(true),
+            policy: OverflowPolicy::DropNewest,
+            drops: DropCounters::new(),
+            encoding: Encoding::Text,
+            seq: AtomicU32::new(0),
+            history: Mutex::new(RefCell::new(History::new())),
+            sessions: AtomicU32::new(0),
+            link: Link::new(),
+            dropped_at_detach: AtomicU32::new(0),
+            staging: [CoreStaging::new(), CoreStaging::new()],
+            staged: Signal::new(),
+            sending: AtomicBool::new(false),
+            sent: Signal::new(),
+            confirm: AtomicBool::new(false),
+            flushing: Signal::new(),
+            dedup: None,
+            repeats: Mutex::new(RefCell::new(Repeats::new())),
         }
     }
 
     /// Create a new logger instance with a custom formatter.
     pub const fn with_custom_style(custom_style: fn(&Record, &mut Writer<'_, N>) -> ()) -> Self {
         Self {
-            buffer: Pipe::new(),
+            buffer: GrantQueue::new(),
             custom_style: Some(custom_style),
+            style: Style::Compact,
+            filter: Mutex::new(RefCell::new(Filter::new(LevelFilter::Trace))),
+            max_level: AtomicU8::new(LevelFilter::Trace as u8),
+            uniform: AtomicBool::new(true),
+            policy: OverflowPolicy::DropNewest,
+            drops: DropCounters::new(),
+            encoding: Encoding::Text,
+            seq: AtomicU32::new(0),
+            history: Mutex::new(RefCell::new(History::new())),
+            sessions: AtomicU32::new(0),
+            link: Link::new(),
+            dropped_at_detach: AtomicU32::new(0),
+            staging: [CoreStaging::new(), CoreStaging::new()],
+            staged: Signal::new(),
+            sending: AtomicBool::new(false),
+            sent: Signal::new(),
+            confirm: AtomicBool::new(false),
+            flushing: Signal::new(),
+            dedup: None,
+            repeats: Mutex::new(RefCell::new(Repeats::new())),
         }
     }
 
-    /// Run the USB logger using the state and USB driver. Never returns.
-    pub async fn run<'d, D>(&'d self, state: &'d mut LoggerState<'d>, driver: D) -> !
+    /// Set the level records must have to be logged, before `set_filter`
+    /// replaces it.
+    pub const fn with_level(mut self, level: LevelFilter) -> Self {
+        self.filter = Mutex::new(RefCell::new(Filter::new(level)));
+        self.max_level = AtomicU8::new(level as u8);
+        self
+    }
+
+    /// Set the built-in style used when no custom style is given.
+    pub const fn with_style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Set whether records are written as text or as binary frames.
+    ///
+    /// With `Encoding::Cobs` the style and custom style are not used, and
+    /// console output is sent as frames at level `frame::LEVEL_CONSOLE`.
+    ///
+    /// With `Encoding::Defmt` records and console output are passed to the
+    /// defmt global logger, which must be the one from `defmt_usb` with this
+    /// logger as its target.
+    pub const fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Set what happens when a record does not fit into the buffer.
+    pub const fn with_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Collapse repeated identical records into a "last message repeated N
+    /// times" line.
+    ///
+    /// Records are identical if their level, target and message match. The
+    /// count is written when a different record arrives, or with the next
+    /// repeat once the first unreported one is older than `interval`, so a
+    /// message that never changes still shows up periodically.
+    pub const fn with_dedup(mut self, interval: Duration) -> Self {
+        self.dedup = Some(interval);
+        self
+    }
+
+    /// Returns the number of bytes and records lost to buffer overflows so far.
+    ///
+    /// Records that did not fit into a staging ring are counted the next time
+    /// the USB task moves staged records into the buffer.
+    pub fn dropped(&self) -> DropStats {
+        self.drops.stats()
+    }
+
+    /// Log a record, waiting for room in the buffer if the overflow policy is
+    /// `OverflowPolicy::Block`. Other policies behave as in `log::Log::log`.
+    pub async fn log_async(&self, record: &Record<'_>) {
+        if !log::Log::enabled(self, record.metadata()) || !self.deduplicate(record) {
+            return;
+        }
+        if self.encoding == Encoding::Defmt {
+            self.write_drop_marker();
+            sink::Sink::write(&RttSink::new(), record);
+            return;
+        }
+        let mut writer = Writer::new(self);
+        self.format(record, &mut writer);
+        if self.policy == OverflowPolicy::Block
+            && writer.line.len() <= N.min(CoreStaging::max_len())
+        {
+            while !writer.try_commit() {
+                yield_now().await;
+            }
+            self.write_drop_marker();
+        } else {
+            self.write_drop_marker();
+            writer.commit();
+        }
+    }
+
+    /// Returns whether a record should be logged, writing the repeat count of
+    /// the previous record first if needed.
+    fn deduplicate(&self, record: &Record) -> bool {
+        let Some(interval) = self.dedup else {
+            return true;
+        };
+        // Formatting the message for the hash is the slow part, so it is done
+        // before taking the lock.
+        let key = dedup::hash(record);
+        match self
+            .repeats
+            .lock(|r| r.borrow_mut().check(key, record.level(), interval))
+        {
+            Check::Log(repeated) => {
+                if let Some(repeated) = repeated {
+                    self.write_repeated(repeated);
+                }
+                true
+            }
+            Check::Suppress => false,
+            Check::Report(repeated) => {
+                self.write_repeated(repeated);
+                false
+            }
+        }
+    }
+
+    /// Write the "last message repeated" line.
+    fn write_repeated(&self, repeated: Repeated) {
+        if self.encoding == Encoding::Defmt {
+            defmt::info!("last message repeated {=u32} times", repeated.count);
+            return;
+        }
+        let mut writer = Writer::new(self);
+        if self.encoding == Encoding::Cobs {
+            let args = format_args!("last message repeated {} times", repeated.count);
+            writer.frame(repeated.level as u8, module_path!(), args);
+        } else {
+            let _ = write!(
+                writer,
+                "[last message repeated {} times]\r\n",
+                repeated.count
+            );
+        }
+        writer.commit();
+    }
+
+    fn format(&self, record: &Record, writer: &mut Writer<'_, N>) {
+        if self.encoding == Encoding::Cobs {
+            writer.frame(record.level() as u8, record.target(), *record.args());
+        } else if let Some(custom_style) = self.custom_style {
+            custom_style(record, writer);
+        } else {
+            let _ = self.style.write(record, writer);
+        }
+    }
+
+    /// Wait until the host has read everything logged so far, including the
+    /// zero length packet after a full one.
+    ///
+    /// Once the output is sent, one more zero length packet is written, which
+    /// the controller only takes once the host has read the packet before it.
+    /// The host sees that as an empty read.
+    ///
+    /// Returns false if that did not happen within `timeout`, for example
+    /// because the host is not listening. Use it before resetting the chip or
+    /// entering deep sleep, so the last records are not lost.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        self.request_confirm();
+        let flushed = async {
+            while !self.is_flushed() {
+                select(self.sent.wait(), Timer::after_millis(DRAIN_POLL_MS)).await;
+            }
+        };
+        with_timeout(timeout, flushed).await.is_ok()
+    }
+
+    /// Ask the USB task to confirm that the host read the output.
+    fn request_confirm(&self) {
+        self.confirm.store(true, Ordering::Relaxed);
+        // Interrupt handlers do not wake the USB task.
+        self.staged.signal(());
+        self.flushing.signal(());
+    }
+
+    /// Returns whether nothing is staged or buffered.
+    fn is_idle(&self) -> bool {
+        critical_section::with(|_| {
+            self.staging.iter().all(CoreStaging::is_empty) && self.buffer.is_empty()
+        })
+    }
+
+    /// Returns whether nothing is staged, buffered or waiting to be read by the
+    /// host.
+    fn is_flushed(&self) -> bool {
+        self.is_idle()
+            && !self.sending.load(Ordering::Relaxed)
+            && !self.confirm.load(Ordering::Relaxed)
+    }
+
+    /// Stage a record on the current core, returning false if it does not fit.
+    ///
+    /// Only thread mode wakes the USB task, since that takes a lock. Records
+    /// staged by interrupt handlers are picked up within `DRAIN_POLL_MS`.
+    fn stage(&self, b: &[u8]) -> bool {
+        let staged = self.staging[current_core() as usize].push(b);
+        if staged && staging::in_thread_mode() {
+            self.staged.signal(());
+        }
+        staged
+    }
+
+    /// Move staged records into the buffer, oldest first across all cores and
+    /// priorities.
+    ///
+    /// Returns false if a record was left staged because the buffer is full
+    /// and the overflow policy is `OverflowPolicy::Block`, or because the
+    /// oldest records cannot be overwritten while they are being sent.
+    fn drain(&self) -> bool {
+        for core in &self.staging {
+            let (bytes, records) = core.collect_drops();
+            if records > 0 {
+                self.drops.add(bytes, records);
+            }
+        }
+        while let Some(ring) = staging::oldest(&self.staging) {
+            let len = ring.len();
+            if !self.write_all_or_nothing(len, |dst| ring.peek(dst)) {
+                let retry = match self.policy {
+                    OverflowPolicy::Block => true,
+                    OverflowPolicy::OverwriteOldest => self.buffer.is_reading(),
+                    OverflowPolicy::DropNewest => false,
+                };
+                if retry {
+                    return false;
+                }
+                self.drops.add(len, 1);
+            }
+            ring.pop(len);
+        }
+        true
+    }
+
+    /// Reserve `len` bytes in the buffer and let `fill` write the record into
+    /// them, or write nothing if they do not fit.
+    fn write_all_or_nothing(&self, len: usize, fill: impl FnOnce(&mut [u8])) -> bool {
+        // The history must see records in the same order as the buffer.
+        critical_section::with(|_| {
+            if self.policy == OverflowPolicy::OverwriteOldest {
+                self.make_room(len);
+            }
+            let Some(mut grant) = self.buffer.grant(len) else {
+                return false;
+            };
+            fill(grant.buf());
+            self.history.lock(|h| h.borrow_mut().push(grant.buf()));
+            crashlog::record(grant.buf());
+            grant.commit(len);
+            true
+        })
+    }
+
+    /// Discard whole records from the front of the buffer until `len` bytes
+    /// fit.
+    ///
+    /// The bytes being sent cannot be discarded, so this may stop short.
+    fn make_room(&self, len: usize) {
+        let delimiter = self.encoding.delimiter();
+        let mut bytes = 0;
+        let mut records = 0;
+        let mut mid_line = false;
+        while mid_line || !self.buffer.fits(len) {
+            let Some(grant) = self.buffer.read() else {
+                break;
+            };
+            let data = grant.buf();
+            let n = match data.iter().position(|&b| b == delimiter) {
+                Some(i) => i + 1,
+                None => data.len(),
+            };
+            mid_line = data[n - 1] != delimiter;
+            bytes += n;
+            if !mid_line {
+                records += 1;
+            }
+            grant.release(n);
+        }
+        if bytes > 0 {
+            self.drops.add(bytes, records);
+        }
+    }
+
+    /// Write the "records dropped" marker once there is room for it.
+    fn write_drop_marker(&self) {
+        let pending = self.drops.pending();
+        if pending == 0 {
+            return;
+        }
+        if self.encoding == Encoding::Defmt {
+            // Clear first, as a dropped marker counts as pending again.
+            self.drops.clear_pending(pending);
+            defmt::warn!("{=u32} records dropped", pending);
+            return;
+        }
+        let mut writer = Writer::new(self);
+        if self.encoding == Encoding::Cobs {
+            let args = format_args!("{} records dropped", pending);
+            writer.frame(log::Level::Warn as u8, module_path!(), args);
+        } else {
+            let _ = write!(writer, "[{} records dropped]\r\n", pending);
+        }
+        if writer.try_commit() {
+            self.drops.clear_pending(pending);
+        }
+    }
+
+    /// Replace the log filter with one parsed from an env_logger-style directive
+    /// string such as `main=debug,mcp=trace,warn`.
+    ///
+    /// The global maximum level is raised or lowered to match the new filter,
+    /// taking the other sinks into account if the fan-out logger is installed.
+    pub fn set_filter(&self, spec: &str) -> Result<(), FilterError> {
+        let filter = Filter::parse(spec)?;
+        self.filter.lock(|f| *f.borrow_mut() = filter);
+        self.max_level
+            .store(filter.max_level() as u8, Ordering::Relaxed);
+        self.uniform.store(filter.is_uniform(), Ordering::Relaxed);
+        sink::update_max_level(filter.max_level());
+        Ok(())
+    }
+
+    /// Returns the last known state of the USB link and the control lines.
+    pub fn link_state(&self) -> LinkState {
+        self.link.state()
+    }
+
+    /// Returns a copy of the current log filter.
+    pub fn filter(&self) -> Filter {
+        self.filter.lock(|f| *f.borrow())
+    }
+
+    /// Run the USB logger using the state, USB driver and device configuration. Never returns.
+    ///
+    /// Output is only sent while a program on the host has the port open, as
+    /// signalled by DTR, and the bus is not suspended. In between, records are
+    /// buffered according to the overflow policy.
+    pub async fn run<'d, D>(
+        &'d self,
+        state: &'d mut LoggerState<'d>,
+        driver: D,
+        logger_config: LoggerConfig<'d>,
+    ) -> !
     where
         D: Driver<'d>,
         Self: 'd,
     {
-        let mut config = Config::new(0xc0de, 0xcafe);
-        config.manufacturer = Some("Embassy");
-        config.product = Some("USB-serial logger");
-        config.serial_number = None;
-        config.max_power = 100;
-        config.max_packet_size_0 = MAX_PACKET_SIZE;
-
-        // Required for windows compatiblity.
-        // https://developer.nordicsemi.com/nRF_Connect_SDK/doc/1.9.1/kconfig/CONFIG_CDC_ACM_IAD.html#help
-        config.device_class = 0xEF;
-        config.device_sub_class = 0x02;
-        config.device_protocol = 0x01;
-        config.composite_with_iads = true;
-
-        let mut builder = Builder::new(
-            driver,
-            config,
-            &mut state.config_descriptor,
-            &mut state.bos_descriptor,
-            &mut state.msos_descriptor,
-            &mut state.control_buf,
-        );
-
-        // Create classes on the builder.
-        let class = CdcAcmClass::new(&mut builder, &mut state.state, MAX_PACKET_SIZE as u16);
-        let (mut sender, mut receiver) = class.split();
-
-        // Build the builder.
-        let mut device = builder.build();
-        loop {
-            let run_fut = device.run();
-            let class_fut = self.run_logger_class(&mut sender, &mut receiver);
-            join(run_fut, class_fut).await;
-        }
+        DeviceBuilder::new(driver, logger_config, state, self)
+            .build()
+            .run()
+            .await
     }
 
     async fn run_logger_class<'d, D>(
         &self,
         sender: &mut Sender<'d, D>,
         receiver: &mut Receiver<'d, D>,
+        control: &ControlChanged<'d>,
     ) where
         D: Driver<'d>,
     {
-        let log_fut = async {
-            let mut rx: [u8; MAX_PACKET_SIZE as usize] = [0; MAX_PACKET_SIZE as usize];
-            sender.wait_connection().await;
+        let drain_fut = async {
             loop {
-                let len = self.buffer.read(&mut rx[..]).await;
-                let _ = sender.write_packet(&rx[..len]).await;
-                if len as u8 == MAX_PACKET_SIZE {
-                    let _ = sender.write_packet(&[]).await;
+                select(self.staged.wait(), Timer::after_millis(DRAIN_POLL_MS)).await;
+                while !self.drain() {
+                    Timer::after_millis(1).await;
                 }
             }
         };
-        let discard_fut = async {
-            let mut discard_buf: [u8; MAX_PACKET_SIZE as usize] = [0; MAX_PACKET_SIZE as usize];
+        let log_fut = async {
+            let mut packets = Packets::new(sender, control, &self.link);
+            loop {
+                packets.wait_attached().await;
+                let lost = self.begin_session();
+                let Err(Detached) = self.run_session(&mut packets, lost).await;
+            }
+        };
+        let console_fut = async {
+            let mut rx: [u8; MAX_PACKET_SIZE as usize] = [0; MAX_PACKET_SIZE as usize];
+            let mut console = Console::new();
             receiver.wait_connection().await;
             loop {
-                let _ = receiver.read_packet(&mut discard_buf).await;
+                match receiver.read_packet(&mut rx).await {
+                    Ok(len) => {
+                        console.process(&rx[..len], &mut ConsoleWriter(self), &mut |line, out| {
+                            self.execute(line, out)
+                        })
+                    }
+                    Err(_) => receiver.wait_connection().await,
+                }
+            }
+        };
+
+        join3(drain_fut, log_fut, console_fut).await;
+    }
+
+    /// Returns the number of records lost since the last session ended.
+    ///
+    /// The session banner reports them, so no separate drop marker is written.
+    fn begin_session(&self) -> u32 {
+        self.drops.clear_pending(self.drops.pending());
+        let dropped = self.drops.stats().records;
+        dropped.wrapping_sub(self.dropped_at_detach.load(Ordering::Relaxed))
+    }
+
+    /// Send the replays and then live output until the host detaches.
+    ///
+    /// The replays count as being sent for `flush`, as they include the
+    /// records cleared from the buffer.
+    async fn run_session<P: Port, C: ControlLines>(
+        &self,
+        packets: &mut Packets<'_, P, C>,
+        lost: u32,
+    ) -> Result<core::convert::Infallible, Detached> {
+        self.sending.store(true, Ordering::Relaxed);
+        let mut unsent = (0, 0);
+        let result = self.send_session(packets, lost, &mut unsent).await;
+        self.dropped_at_detach
+            .store(self.drops.stats().records, Ordering::Relaxed);
+        // The packet being sent when the host detached never arrived, so the
+        // next session reports its records as lost.
+        let (bytes, records) = unsent;
+        if bytes > 0 {
+            self.drops.add(bytes, records);
+        }
+        self.sending.store(false, Ordering::Relaxed);
+        self.sent.signal(());
+        result
+    }
+
+    /// Everything `run_session` sends, marking output as sent once the host
+    /// has it, and setting `unsent` to the bytes and records released from
+    /// the buffer without reaching the host.
+    async fn send_session<P: Port, C: ControlLines>(
+        &self,
+        packets: &mut Packets<'_, P, C>,
+        lost: u32,
+        unsent: &mut (usize, u32),
+    ) -> Result<core::convert::Infallible, Detached> {
+        self.replay_previous_boot(packets).await?;
+        self.replay_history(packets, lost).await?;
+        self.sending.store(false, Ordering::Relaxed);
+        self.sent.signal(());
+        loop {
+            // Only the bytes of one packet are held while it is being sent.
+            if let Some(grant) = self.buffer.read_max(MAX_PACKET_SIZE as usize) {
+                // The bytes stay in the buffer until the host has them.
+                let len = grant.buf().len();
+                let sent = packets.send(&grant.buf()[..len]).await;
+                if sent.is_err() {
+                    let delimiter = self.encoding.delimiter();
+                    let records = grant.buf().iter().filter(|&&b| b == delimiter).count();
+                    *unsent = (len, records as u32);
+                }
+                grant.release(len);
+                self.sent.signal(());
+                sent?;
+                continue;
+            }
+            if self.confirm.load(Ordering::Relaxed) && self.is_idle() {
+                packets.confirm().await?;
+                // Output logged in the meantime needs to be confirmed again.
+                if self.is_idle() {
+                    self.confirm.store(false, Ordering::Relaxed);
+                }
+                self.sent.signal(());
+                continue;
+            }
+            match select3(self.buffer.wait(), packets.changed(), self.flushing.wait()).await {
+                Either3::Second(()) => {
+                    if !packets.attached() {
+                        return Err(Detached);
+                    }
+                }
+                Either3::First(()) | Either3::Third(()) => {}
+            }
+        }
+    }
+
+    /// Send what the previous boot logged before it reset, once per boot.
+    ///
+    /// In text mode every line is prefixed with `previous boot | `, and with
+    /// `Encoding::Cobs` a `previous boot` frame comes first.
+    async fn replay_previous_boot<P: Port, C: ControlLines>(
+        &self,
+        packets: &mut Packets<'_, P, C>,
+    ) -> Result<(), Detached> {
+        let Some(previous) = crashlog::take_previous_boot() else {
+            return Ok(());
+        };
+        let delimiter = match self.encoding {
+            Encoding::Text => b'\n',
+            Encoding::Cobs | Encoding::Defmt => 0,
+        };
+        if self.encoding == Encoding::Cobs {
+            // Frames carry no prefix, so a marker frame tells the host that
+            // the ones up to the session banner are from the previous boot.
+            let mut writer = Writer::new(self);
+            let args = format_args!("previous boot");
+            writer.frame(log::Level::Info as u8, module_path!(), args);
+            packets.write(writer.bytes()).await?;
+        }
+        // The oldest line was partly overwritten if the history wrapped.
+        let mut skipping = previous.wrapped;
+        let mut line_start = true;
+        for &b in previous.history.iter().flat_map(|part| part.iter()) {
+            if skipping {
+                skipping = b != delimiter;
+                continue;
+            }
+            if self.encoding == Encoding::Text && line_start {
+                packets.write(PREVIOUS_BOOT_PREFIX).await?;
+            }
+            packets.write(&[b]).await?;
+            line_start = b == delimiter;
+        }
+        if let Some(crash) = previous.crash {
+            let crash = core::str::from_utf8(crash).unwrap_or("<invalid crash message>");
+            let mut writer = Writer::new(self);
+            match self.encoding {
+                Encoding::Text => {
+                    let _ = write!(writer, "crash: {}\r\n", crash);
+                    packets.write(PREVIOUS_BOOT_PREFIX).await?;
+                }
+                Encoding::Cobs => {
+                    let args = format_args!("previous boot crashed: {}", crash);
+                    writer.frame(log::Level::Error as u8, module_path!(), args);
+                }
+                // Sent through the buffer, after the replay of the history.
+                Encoding::Defmt => defmt::error!("previous boot crashed: {=str}", crash),
+            }
+            packets.write(writer.bytes()).await?;
+        }
+        packets.flush().await
+    }
+
+    /// Start a new session with a banner and the output kept in the history.
+    ///
+    /// Everything still in the buffer is also in the history, so the buffer is
+    /// cleared and its contents are sent as part of the history instead. With
+    /// `Encoding::Defmt` the banner goes through the buffer, so it follows the
+    /// replay.
+    async fn replay_history<P: Port, C: ControlLines>(
+        &self,
+        packets: &mut Packets<'_, P, C>,
+        lost: u32,
+    ) -> Result<(), Detached> {
+        let (mut pos, end) = critical_section::with(|_| {
+            self.buffer.clear();
+            self.history.lock(|h| {
+                let h = h.borrow();
+                (h.start(), h.end())
+            })
+        });
+        let session = self.sessions.fetch_add(1, Ordering::Relaxed) + 1;
+        let ms = Instant::now().as_millis();
+        let mut writer = Writer::new(self);
+        let delimiter = match self.encoding {
+            Encoding::Text => {
+                let _ = write!(
+                    writer,
+                    "--- session {} at {}.{:03} s, {} records lost, replaying {} bytes ---\r\n",
+                    session,
+                    ms / 1000,
+                    ms % 1000,
+                    lost,
+                    end - pos
+                );
+                b'\n'
+            }
+            Encoding::Cobs => {
+                let args = format_args!(
+                    "session {}, {} records lost, replaying {} bytes",
+                    session,
+                    lost,
+                    end - pos
+                );
+                writer.frame(log::Level::Info as u8, module_path!(), args);
+                0
+            }
+            Encoding::Defmt => {
+                defmt::info!(
+                    "session {=u32}, {=u32} records lost, replaying {=u64} bytes",
+                    session,
+                    lost,
+                    end - pos
+                );
+                0
             }
         };
+        packets.write(writer.bytes()).await?;
+
+        // The oldest record is incomplete once the history has wrapped.
+        let mut skipping = pos > 0;
+        let mut chunk = [0u8; MAX_PACKET_SIZE as usize];
+        while pos < end {
+            let (start, len) = self.history.lock(|h| h.borrow().read(pos, end, &mut chunk));
+            if start != pos {
+                // New output overwrote the part that had not been sent yet.
+                skipping = true;
+            }
+            pos = start + len as u64;
+            for &b in &chunk[..len] {
+                if skipping {
+                    skipping = b != delimiter;
+                    continue;
+                }
+                packets.write(&[b]).await?;
+            }
+        }
+        packets.flush().await
+    }
+
+    /// Write console output, waiting for room in the buffer rather than
+    /// losing it to the overflow policy. Gives up once the host detaches.
+    pub(crate) async fn write_console(&self, s: &str) {
+        let staging = &self.staging[current_core() as usize];
+        while !(staging.is_empty() && self.buffer.fits(s.len().min(N))) {
+            if !self.link.state().attached() {
+                return;
+            }
+            Timer::after_millis(DRAIN_POLL_MS).await;
+        }
+        let _ = ConsoleWriter(self).write_str(s);
+    }
 
-        join(log_fut, discard_fut).await;
+    /// Run a console line, handling the logger's built-in commands before
+    /// falling back to the registered ones.
+    fn execute(&self, line: &str, out: &mut dyn core::fmt::Write) {
+        match split_command(line) {
+            ("log", "") => {
+                let _ = write!(out, "{}\r\n", self.filter());
+            }
+            ("log", spec) => match self.set_filter(spec) {
+                Ok(()) => {
+                    let _ = write!(out, "log filter set to {}\r\n", self.filter());
+                }
+                Err(e) => {
+                    let _ = write!(out, "error: {}\r\n", e);
+                }
+            },
+            ("help", _) => {
+                let _ = write!(out, "log [directives] - show or set the log filter\r\n");
+                COMMANDS.dispatch(line, out);
+            }
+            _ => COMMANDS.dispatch(line, out),
+        }
     }
 
     /// Creates the futures needed for the logger from a given class
     /// This can be used in cases where the usb device is already in use for another connection
+    ///
+    /// `device::DeviceBuilder` builds such a device with the logger already
+    /// added, and checks that the descriptors of the other classes fit.
+    ///
+    /// USB suspend is not seen here, as the device and its handler belong to
+    /// the caller, so only DTR decides whether output is sent.
     pub async fn create_future_from_class<'d, D>(&'d self, class: CdcAcmClass<'d, D>)
     where
         D: Driver<'d>,
     {
-        let (mut sender, mut receiver) = class.split();
+        let (mut sender, mut receiver, control) = class.split_with_control();
         loop {
-            self.run_logger_class(&mut sender, &mut receiver).await;
+            self.run_logger_class(&mut sender, &mut receiver, &control)
+                .await;
         }
     }
 }
 
 impl<const N: usize> log::Log for UsbLogger<N> {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if metadata.level() as u8 > self.max_level.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.uniform.load(Ordering::Relaxed) || self.filter.lock(|f| f.borrow().enabled(metadata))
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            if let Some(custom_style) = self.custom_style {
-                custom_style(record, &mut Writer(&self.buffer));
-            } else {
-                let _ = write!(Writer(&self.buffer), "{}\r\n", record.args());
-            }
+        if !self.enabled(record.metadata()) || !self.deduplicate(record) {
+            return;
+        }
+        if self.encoding == Encoding::Defmt {
+            self.write_drop_marker();
+            sink::Sink::write(&RttSink::new(), record);
+            return;
         }
+        let mut writer = Writer::new(self);
+        self.format(record, &mut writer);
+        self.write_drop_marker();
+        writer.commit();
     }
 
-    fn flush(&self) {}
+    /// Wait up to `FLUSH_TIMEOUT_MS` for the host to read everything.
+    ///
+    /// This busy-waits, so it only makes progress if the logger runs at a
+    /// higher priority than the caller, for example on an `InterruptExecutor`.
+    /// From async code, use `UsbLogger::flush` instead.
+    fn flush(&self) {
+        self.request_confirm();
+        let start = Instant::now();
+        while !self.is_flushed() && start.elapsed().as_millis() < FLUSH_TIMEOUT_MS {}
+    }
 }
 
-/// A writer that writes to the USB logger buffer.
-pub struct Writer<'d, const N: usize>(&'d Pipe<CS, N>);
+impl<const N: usize> sink::Sink for UsbLogger<N> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        log::Log::enabled(self, metadata)
+    }
+
+    fn write(&self, record: &Record) {
+        log::Log::log(self, record)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.filter().max_level()
+    }
+
+    fn flush(&self) {
+        log::Log::flush(self)
+    }
+}
+
+/// A writer that stages a single record before it is committed to the USB
+/// logger buffer as a whole.
+pub struct Writer<'d, const N: usize> {
+    logger: &'d UsbLogger<N>,
+    line: Line,
+}
+
+impl<'d, const N: usize> Writer<'d, N> {
+    fn new(logger: &'d UsbLogger<N>) -> Self {
+        Self {
+            logger,
+            line: Line::new(),
+        }
+    }
+
+    /// Replace the staged bytes with a binary frame.
+    fn frame(&mut self, level: u8, target: &str, args: core::fmt::Arguments) {
+        let seq = self.logger.seq.fetch_add(1, Ordering::Relaxed);
+        self.line
+            .encode(|buf| frame::encode(buf, seq, level, target, args));
+    }
+
+    /// The staged bytes, with the truncation suffix applied if needed.
+    fn bytes(&mut self) -> &[u8] {
+        self.line.bytes()
+    }
+
+    /// Try to stage the record on the current core.
+    fn try_commit(&mut self) -> bool {
+        let logger = self.logger;
+        logger.stage(self.line.bytes())
+    }
+
+    /// Write the record to the buffer, or account for it as dropped.
+    fn commit(mut self) {
+        if !self.try_commit() {
+            self.logger.staging[current_core() as usize].drop_record(self.line.len());
+        }
+    }
+}
 
 impl<const N: usize> core::fmt::Write for Writer<'_, N> {
     fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
-        // The Pipe is implemented in such way that we cannot
-        // write across the wraparound discontinuity.
-        let b = s.as_bytes();
-        if let Ok(n) = self.0.try_write(b) {
-            if n < b.len() {
-                // We wrote some data but not all, attempt again
-                // as the reason might be a wraparound in the
-                // ring buffer, which resolves on second attempt.
-                let _ = self.0.try_write(&b[n..]);
+        self.line.write_str(s)
+    }
+}
+
+/// Writes console output to the USB logger buffer, one fragment at a time.
+struct ConsoleWriter<'d, const N: usize>(&'d UsbLogger<N>);
+
+impl<const N: usize> core::fmt::Write for ConsoleWriter<'_, N> {
+    fn write_str(&mut self, s: &str) -> Result<(), core::fmt::Error> {
+        if self.0.encoding == Encoding::Cobs {
+            let mut writer = Writer::new(self.0);
+            writer.frame(frame::LEVEL_CONSOLE, "console", format_args!("{}", s));
+            let _ = writer.try_commit();
+        } else if self.0.encoding == Encoding::Defmt {
+            defmt::println!("{=str}", s);
+        } else {
+            for chunk in s.as_bytes().chunks(MAX_RECORD_LEN) {
+                let _ = self.0.stage(chunk);
             }
         }
         Ok(())
@@ -200,51 +990,55 @@ impl<const N: usize> core::fmt::Write for Writer<'_, N> {
 
 /// Initialize and run the USB serial logger, never returns.
 ///
-/// Arguments specify the buffer size, log level and the USB driver, respectively.
+/// Arguments specify the buffer size, log level, the USB driver and either the
+/// flash, whose unique ID becomes the serial number, or a `LoggerConfig`,
+/// respectively. The flash is needed as the RP2040 has no unique ID of its own.
+/// This is a shorthand for `UsbLogger::install` followed by
+/// `LoggerHandle::run`, and panics if a logger is already installed. `$l` must
+/// be a constant expression.
 ///
 /// # Usage
 ///
 /// ```
-/// embassy_usb_logger::run!(1024, log::LevelFilter::Info, driver);
+/// rp2040_project_template::run!(1024, log::LevelFilter::Info, driver, flash = &mut flash);
 /// ```
 ///
-/// # Safety
-///
-/// This macro should only be invoked only once since it is setting the global logging state of the application.
+/// ```
+/// let config = LoggerConfig {
+///     product: Some("I/O expander tester"),
+///     ..LoggerConfig::from_flash(&mut flash)
+/// };
+/// rp2040_project_template::run!(1024, log::LevelFilter::Info, driver, config);
+/// ```
 #[macro_export]
 macro_rules! run {
-    ( $x:expr, $l:expr, $p:ident ) => {
-        static LOGGER: ::rp2040_project_template::UsbLogger<$x> =
-            ::rp2040_project_template::UsbLogger::new();
-        unsafe {
-            let _ = ::log::set_logger_racy(&LOGGER).map(|()| log::set_max_level_racy($l));
-        }
-        let _ = LOGGER
-            .run(&mut ::rp2040_project_template::LoggerState::new(), $p)
-            .await;
+    ( $x:expr, $l:expr, $p:ident, flash = $f:expr ) => {
+        $crate::run!($x, $l, $p, $crate::config::LoggerConfig::from_flash($f))
+    };
+    ( $x:expr, $l:expr, $p:ident, $c:expr ) => {
+        static LOGGER: $crate::UsbLogger<$x> = $crate::UsbLogger::new().with_level($l);
+        $crate::UsbLogger::install(&LOGGER).expect("a global logger is already installed");
+        LOGGER.run(&mut $crate::LoggerState::new(), $p, $c).await
     };
 }
 
 /// Initialize the USB serial logger from a serial class and return the future to run it.
 ///
 /// Arguments specify the buffer size, log level and the serial class, respectively.
+/// This is a shorthand for `UsbLogger::install` followed by
+/// `LoggerHandle::run_with_class`, and panics if a logger is already installed.
+/// `$l` must be a constant expression.
 ///
 /// # Usage
 ///
 /// ```
-/// embassy_usb_logger::with_class!(1024, log::LevelFilter::Info, class);
+/// rp2040_project_template::with_class!(1024, log::LevelFilter::Info, class);
 /// ```
-///
-/// # Safety
-///
-/// This macro should only be invoked only once since it is setting the global logging state of the application.
 #[macro_export]
 macro_rules! with_class {
     ( $x:expr, $l:expr, $p:ident ) => {{
-        static LOGGER: UsbLogger<$x> = UsbLogger::new();
-        unsafe {
-            let _ = ::log::set_logger_racy(&LOGGER).map(|()| log::set_max_level_racy($l));
-        }
+        static LOGGER: $crate::UsbLogger<$x> = $crate::UsbLogger::new().with_level($l);
+        $crate::UsbLogger::install(&LOGGER).expect("a global logger is already installed");
         LOGGER.create_future_from_class($p)
     }};
 }
@@ -254,28 +1048,23 @@ macro_rules! with_class {
 /// The custom style function will be called for each log record and is responsible for writing the log message to the buffer.
 ///
 /// Arguments specify the buffer size, log level, the serial class and the custom style function, respectively.
+/// Panics if a logger is already installed. `$l` must be a constant expression.
 ///
 /// # Usage
 ///
 /// ```
-/// let log_fut = embassy_usb_logger::with_custom_style!(1024, log::LevelFilter::Info, logger_class, |record, writer| {
+/// let log_fut = rp2040_project_template::with_custom_style!(1024, log::LevelFilter::Info, logger_class, |record, writer| {
 ///     use core::fmt::Write;
 ///     let level = record.level().as_str();
 ///     write!(writer, "[{level}] {}\r\n", record.args()).unwrap();
 /// });
 /// ```
-///
-/// # Safety
-///
-/// This macro should only be invoked only once since it is setting the global logging state of the application.
 #[macro_export]
 macro_rules! with_custom_style {
     ( $x:expr, $l:expr, $p:ident, $s:expr ) => {{
-        static LOGGER: ::embassy_usb_logger::UsbLogger<$x> =
-            ::embassy_usb_logger::UsbLogger::with_custom_style($s);
-        unsafe {
-            let _ = ::log::set_logger_racy(&LOGGER).map(|()| log::set_max_level_racy($l));
-        }
+        static LOGGER: $crate::UsbLogger<$x> =
+            $crate::UsbLogger::with_custom_style($s).with_level($l);
+        $crate::UsbLogger::install(&LOGGER).expect("a global logger is already installed");
         LOGGER.create_future_from_class($p)
     }};
 }
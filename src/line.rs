@@ -0,0 +1,79 @@
+//! The fixed buffer a record is formatted into before it is queued.
+
+use core::fmt::{self, Write};
+
+use crate::MAX_RECORD_LEN;
+
+/// Ends a record that was cut to fit.
+const TRUNCATED_SUFFIX: &[u8] = b"...\r\n";
+
+/// A record formatted into a fixed buffer, truncated with `...\r\n` if it
+/// does not fit.
+pub(crate) struct Line {
+    buf: [u8; MAX_RECORD_LEN],
+    len: usize,
+    truncated: bool,
+}
+
+impl Line {
+    pub(crate) fn new() -> Self {
+        Self {
+            buf: [0; MAX_RECORD_LEN],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    /// The number of bytes formatted so far, before any truncation suffix.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Replace the contents with the bytes `f` encodes into the buffer,
+    /// returning their length.
+    pub(crate) fn encode(&mut self, f: impl FnOnce(&mut [u8; MAX_RECORD_LEN]) -> usize) {
+        self.len = f(&mut self.buf);
+        self.truncated = false;
+    }
+
+    /// The formatted bytes, with the truncation suffix applied if needed.
+    pub(crate) fn bytes(&mut self) -> &[u8] {
+        if self.truncated {
+            let mut end = self.len.min(MAX_RECORD_LEN - TRUNCATED_SUFFIX.len());
+            while !is_char_boundary(&self.buf[..self.len], end) {
+                end -= 1;
+            }
+            self.buf[end..end + TRUNCATED_SUFFIX.len()].copy_from_slice(TRUNCATED_SUFFIX);
+            self.len = end + TRUNCATED_SUFFIX.len();
+            self.truncated = false;
+        }
+        &self.buf[..self.len]
+    }
+
+    /// The formatted text without its line ending.
+    pub(crate) fn text(&mut self) -> &str {
+        let bytes = self.bytes();
+        let bytes = bytes.strip_suffix(b"\r\n").unwrap_or(bytes);
+        core::str::from_utf8(bytes).unwrap_or("<invalid utf-8>")
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let b = s.as_bytes();
+        let room = MAX_RECORD_LEN - self.len;
+        let n = if b.len() > room {
+            self.truncated = true;
+            room
+        } else {
+            b.len()
+        };
+        self.buf[self.len..self.len + n].copy_from_slice(&b[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn is_char_boundary(b: &[u8], i: usize) -> bool {
+    i >= b.len() || (b[i] & 0xc0) != 0x80
+}
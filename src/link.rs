@@ -0,0 +1,71 @@
+//! Tracking of whether a host is listening to the logger.
+
+use embassy_sync::signal::Signal;
+use embassy_usb::Handler;
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::CS;
+
+/// The state of the USB link and the CDC control lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LinkState {
+    /// The bus is suspended, for example because the host went to sleep.
+    pub suspended: bool,
+    /// Data Terminal Ready, raised by the host while a program has the port open.
+    pub dtr: bool,
+    /// Request To Send, as last set by the host.
+    pub rts: bool,
+}
+
+impl LinkState {
+    /// Whether output sent now would be read by the host.
+    pub fn attached(&self) -> bool {
+        self.dtr && !self.suspended
+    }
+}
+
+pub(crate) struct Link {
+    suspended: AtomicBool,
+    dtr: AtomicBool,
+    rts: AtomicBool,
+    changed: Signal<CS, ()>,
+}
+
+impl Link {
+    pub(crate) const fn new() -> Self {
+        Self {
+            suspended: AtomicBool::new(false),
+            dtr: AtomicBool::new(false),
+            rts: AtomicBool::new(false),
+            changed: Signal::new(),
+        }
+    }
+
+    pub(crate) fn state(&self) -> LinkState {
+        LinkState {
+            suspended: self.suspended.load(Ordering::Relaxed),
+            dtr: self.dtr.load(Ordering::Relaxed),
+            rts: self.rts.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn set_lines(&self, dtr: bool, rts: bool) {
+        self.dtr.store(dtr, Ordering::Relaxed);
+        self.rts.store(rts, Ordering::Relaxed);
+    }
+
+    /// Wait for the next suspend or resume.
+    pub(crate) async fn changed(&self) {
+        self.changed.wait().await
+    }
+}
+
+/// Passes USB device events to the logger's link state.
+pub(crate) struct LinkHandler<'d>(pub(crate) &'d Link);
+
+impl Handler for LinkHandler<'_> {
+    fn suspended(&mut self, suspended: bool) {
+        self.0.suspended.store(suspended, Ordering::Relaxed);
+        self.0.changed.signal(());
+    }
+}
@@ -8,27 +8,36 @@
 #![allow(async_fn_in_trait)]
 use core::panic::PanicInfo;
 
-use cortex_m::asm::delay;
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
-    loop {
-        log::info!("{}", info);
-        delay(1000000);
-    }
+    rp2040_project_template::panic::handle(info)
 }
 
+#[cfg(not(feature = "defmt-usb"))]
 use defmt_rtt as _;
-use embassy_executor::Spawner;
+use embassy_executor::{Executor, InterruptExecutor, Spawner};
 use embassy_rp::bind_interrupts;
+use embassy_rp::flash::{Blocking, Flash};
 use embassy_rp::i2c::{self, Config, InterruptHandler};
+use embassy_rp::interrupt;
+use embassy_rp::interrupt::{InterruptExt, Priority};
+use embassy_rp::multicore::{spawn_core1, Stack};
+use embassy_rp::peripherals::FLASH;
 use embassy_rp::peripherals::I2C1;
 use embassy_rp::peripherals::USB;
 use embassy_rp::*;
 use embedded_hal_1::i2c::I2c;
 
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
 use mcp230xx::*;
-use rp2040_project_template::run;
+use rp2040_project_template::config::LoggerConfig;
+use rp2040_project_template::flashlog::{self, FlashSink};
+#[cfg(feature = "defmt-usb")]
+use rp2040_project_template::frame::Encoding;
+use rp2040_project_template::panic::{self as panic_handling, PanicAction};
+use rp2040_project_template::sink;
+use rp2040_project_template::{console, LoggerState, UsbLogger};
+use static_cell::StaticCell;
 
 bind_interrupts!(struct Irqs {
     I2C1_IRQ => InterruptHandler<I2C1>;
@@ -36,6 +45,39 @@ bind_interrupts!(struct Irqs {
 
 });
 
+// The logger runs at a higher priority than `main`, so it can still drain its
+// buffer while a panic is being reported.
+static EXECUTOR_HIGH: InterruptExecutor = InterruptExecutor::new();
+
+// The I/O expander is polled from core1, and both cores log to the same port.
+static mut CORE1_STACK: Stack<4096> = Stack::new();
+static EXECUTOR1: StaticCell<Executor> = StaticCell::new();
+
+// The I/O expander is polled every 100 ms, so collapse unchanged readings.
+// The level is the logger's own, so the `log` console command can raise it.
+#[cfg(not(feature = "defmt-usb"))]
+static USB_LOGGER: UsbLogger<1024> = UsbLogger::new()
+    .with_level(log::LevelFilter::Info)
+    .with_dedup(Duration::from_secs(10));
+// Without a probe, defmt output is sent over USB, and so are the log records.
+#[cfg(feature = "defmt-usb")]
+static USB_LOGGER: UsbLogger<1024> = UsbLogger::new()
+    .with_level(log::LevelFilter::Info)
+    .with_dedup(Duration::from_secs(10))
+    .with_encoding(Encoding::Defmt);
+#[cfg(not(feature = "defmt-usb"))]
+static RTT_LOGGER: sink::RttSink = sink::RttSink::new();
+// The board runs unattended for days, so warnings and errors are also kept in
+// flash. The info records come in at 10 Hz and would wear it out in days.
+static FLASH_LOG: FlashSink<1024> = FlashSink::new();
+
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+#[interrupt]
+unsafe fn SWI_IRQ_1() {
+    EXECUTOR_HIGH.on_interrupt()
+}
+
 #[allow(dead_code)]
 mod mcp23017 {
     pub const ADDR: u8 = 0x20; // default addr
@@ -85,23 +127,71 @@ mod mcp23017 {
 }
 
 #[embassy_executor::task]
-async fn logger_task(driver: usb::Driver<'static, USB>) {
-    run!(1024, log::LevelFilter::Info, driver);
+async fn logger_task(driver: usb::Driver<'static, USB>, config: LoggerConfig<'static>) {
+    USB_LOGGER
+        .run(&mut LoggerState::new(), driver, config)
+        .await;
+}
+
+#[embassy_executor::task]
+async fn flash_log_task(flash: Flash<'static, FLASH, Blocking, FLASH_SIZE>) {
+    FLASH_LOG
+        .run(flash, flashlog::partition(), &USB_LOGGER)
+        .await
 }
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
     let driver = usb::Driver::new(p.USB, Irqs);
-    spawner.spawn(logger_task(driver)).unwrap();
+    // The ID is read before the flash sink starts erasing and programming.
+    let mut flash = Flash::new_blocking(p.FLASH);
+    let logger_config = LoggerConfig::from_flash(&mut flash);
+    console::register("uptime", "print the time since boot", |_, out| {
+        let _ = write!(out, "{} ms\r\n", Instant::now().as_millis());
+    })
+    .unwrap();
+    console::register(
+        "flashlog",
+        "print the log kept in flash",
+        flashlog::dump_command,
+    )
+    .unwrap();
+    sink::add(&USB_LOGGER, log::LevelFilter::Trace).unwrap();
+    sink::add(&FLASH_LOG, log::LevelFilter::Warn).unwrap();
+    #[cfg(not(feature = "defmt-usb"))]
+    sink::add(&RTT_LOGGER, log::LevelFilter::Debug).unwrap();
+    #[cfg(feature = "defmt-usb")]
+    rp2040_project_template::defmt_usb::set_target(&USB_LOGGER);
+    sink::install().unwrap();
+    panic_handling::set_action(PanicAction::WatchdogReboot);
+    interrupt::SWI_IRQ_1.set_priority(Priority::P2);
+    let high_spawner = EXECUTOR_HIGH.start(interrupt::SWI_IRQ_1);
+    high_spawner
+        .spawn(logger_task(driver, logger_config))
+        .unwrap();
+    spawner.spawn(flash_log_task(flash)).unwrap();
     let sda = p.PIN_2;
     let scl = p.PIN_3;
 
-    Timer::after_secs(5).await;
+    if let Some(report) = panic_handling::previous_panic() {
+        log::warn!("reset after panic #{}: {}", report.count, report.message);
+    }
 
     log::info!("set up i2c ");
-    let mut i2c = i2c::I2c::new_blocking(p.I2C1, scl, sda, Config::default());
+    let i2c = i2c::I2c::new_blocking(p.I2C1, scl, sda, Config::default());
+    spawn_core1(
+        p.CORE1,
+        unsafe { &mut *core::ptr::addr_of_mut!(CORE1_STACK) },
+        move || {
+            let executor1 = EXECUTOR1.init(Executor::new());
+            executor1.run(|spawner| spawner.spawn(i2c_task(i2c)).unwrap());
+        },
+    );
+}
 
+#[embassy_executor::task]
+async fn i2c_task(mut i2c: i2c::I2c<'static, I2C1, i2c::Blocking>) {
     use mcp23017::*;
 
     log::info!("init mcp23017 config for IxpandO");
@@ -115,7 +205,12 @@ async fn main(spawner: Spawner) {
 
         i2c.write_read(mcp23017::ADDR, &[GPIOB], &mut portb)
             .unwrap();
-        log::info!("portb = {:08b}", portb[0]);
+        rp2040_project_template::log_ratelimited!(
+            Duration::from_secs(1),
+            log::Level::Info,
+            "portb = {:08b}",
+            portb[0]
+        );
 
         // get a register dump
         // log::info!("getting register dump");
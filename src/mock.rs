@@ -0,0 +1,321 @@
+//! A mock USB driver, so the logger can be tested on the host.
+//!
+//! Packets written to IN endpoints are recorded in a `Host`, which the tests
+//! share with the driver and use to set the control lines and send control
+//! requests. The bus never reports anything.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use embassy_sync::signal::Signal;
+use embassy_usb::driver::{
+    self, Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo,
+    EndpointType, Event, Unsupported,
+};
+
+use crate::packets::{ControlLines, Port};
+use crate::{CS, MAX_PACKET_SIZE};
+
+/// The host side of the mock bus.
+#[derive(Default)]
+pub(crate) struct Host {
+    /// Every packet written to an IN endpoint, in order, whether or not the
+    /// host has read it yet.
+    pub(crate) packets: Vec<Vec<u8>>,
+    pub(crate) dtr: bool,
+    pub(crate) rts: bool,
+    /// Make endpoint writes fail, as if the device had been unplugged.
+    pub(crate) disabled: bool,
+    /// SETUP packets to send on the control pipe, first to last.
+    pub(crate) setup: Vec<[u8; 8]>,
+    /// Everything the device sent in the data stage of control transfers.
+    pub(crate) control_in: Vec<u8>,
+    /// Stop reading packets, so the one in the controller stays there and the
+    /// next write to the endpoint waits.
+    pub(crate) stalled: bool,
+}
+
+impl Host {
+    /// The lengths of the packets received so far.
+    pub(crate) fn packet_lens(&self) -> Vec<usize> {
+        self.packets.iter().map(Vec::len).collect()
+    }
+}
+
+pub(crate) struct MockDriver {
+    host: Rc<RefCell<Host>>,
+    next_index: usize,
+}
+
+impl MockDriver {
+    pub(crate) fn new(host: &Rc<RefCell<Host>>) -> Self {
+        Self {
+            host: host.clone(),
+            next_index: 1,
+        }
+    }
+
+    fn info(
+        &mut self,
+        dir: Direction,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+    ) -> EndpointInfo {
+        let index = self.next_index;
+        self.next_index += 1;
+        EndpointInfo {
+            addr: EndpointAddress::from_parts(index, dir),
+            ep_type,
+            max_packet_size,
+            interval_ms: 0,
+        }
+    }
+}
+
+impl<'a> driver::Driver<'a> for MockDriver {
+    type EndpointOut = EndpointOut;
+    type EndpointIn = EndpointIn;
+    type ControlPipe = ControlPipe;
+    type Bus = Bus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval_ms: u8,
+    ) -> Result<EndpointOut, EndpointAllocError> {
+        Ok(EndpointOut {
+            info: self.info(Direction::Out, ep_type, max_packet_size),
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval_ms: u8,
+    ) -> Result<EndpointIn, EndpointAllocError> {
+        Ok(EndpointIn {
+            info: self.info(Direction::In, ep_type, max_packet_size),
+            host: self.host.clone(),
+            armed: false,
+        })
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (Bus, ControlPipe) {
+        (
+            Bus,
+            ControlPipe {
+                max_packet_size: control_max_packet_size as usize,
+                host: self.host,
+            },
+        )
+    }
+}
+
+pub(crate) struct EndpointIn {
+    info: EndpointInfo,
+    host: Rc<RefCell<Host>>,
+    /// Whether a packet was written, which the host reads unless it stalled.
+    armed: bool,
+}
+
+impl driver::Endpoint for EndpointIn {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {}
+}
+
+impl driver::EndpointIn for EndpointIn {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        // Like the RP2040's, the controller holds one packet, so a write waits
+        // until the host has read the one before and is done once its packet
+        // is in the controller.
+        core::future::poll_fn(|_| match self.armed && self.host.borrow().stalled {
+            true => core::task::Poll::Pending,
+            false => core::task::Poll::Ready(()),
+        })
+        .await;
+        let mut host = self.host.borrow_mut();
+        if host.disabled {
+            return Err(EndpointError::Disabled);
+        }
+        if buf.len() > self.info.max_packet_size as usize {
+            return Err(EndpointError::BufferOverflow);
+        }
+        host.packets.push(buf.to_vec());
+        self.armed = true;
+        Ok(())
+    }
+}
+
+pub(crate) struct EndpointOut {
+    info: EndpointInfo,
+}
+
+impl driver::Endpoint for EndpointOut {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {}
+}
+
+impl driver::EndpointOut for EndpointOut {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EndpointError> {
+        core::future::pending().await
+    }
+}
+
+pub(crate) struct ControlPipe {
+    max_packet_size: usize,
+    host: Rc<RefCell<Host>>,
+}
+
+impl driver::ControlPipe for ControlPipe {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        let setup = {
+            let mut host = self.host.borrow_mut();
+            (!host.setup.is_empty()).then(|| host.setup.remove(0))
+        };
+        match setup {
+            Some(setup) => setup,
+            None => core::future::pending().await,
+        }
+    }
+
+    async fn data_out(
+        &mut self,
+        _buf: &mut [u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<usize, EndpointError> {
+        Err(EndpointError::Disabled)
+    }
+
+    async fn data_in(
+        &mut self,
+        data: &[u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<(), EndpointError> {
+        self.host.borrow_mut().control_in.extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn accept(&mut self) {}
+
+    async fn reject(&mut self) {}
+
+    async fn accept_set_address(&mut self, _addr: u8) {}
+}
+
+pub(crate) struct Bus;
+
+impl driver::Bus for Bus {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        core::future::pending().await
+    }
+
+    fn endpoint_set_enabled(&mut self, _ep_addr: EndpointAddress, _enabled: bool) {}
+
+    fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+/// A serial port on a bulk IN endpoint of the mock driver, with the control
+/// lines set by the host.
+pub(crate) struct SerialPort {
+    ep: EndpointIn,
+}
+
+impl SerialPort {
+    pub(crate) fn new(driver: &mut MockDriver) -> Self {
+        let ep = driver::Driver::alloc_endpoint_in(
+            driver,
+            EndpointType::Bulk,
+            MAX_PACKET_SIZE as u16,
+            0,
+        );
+        Self { ep: ep.unwrap() }
+    }
+}
+
+impl Port for SerialPort {
+    async fn wait_connection(&mut self) {}
+
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        driver::EndpointIn::write(&mut self.ep, data).await
+    }
+
+    fn dtr(&self) -> bool {
+        self.ep.host.borrow().dtr
+    }
+
+    fn rts(&self) -> bool {
+        self.ep.host.borrow().rts
+    }
+}
+
+/// Control lines that only change when the test calls `change`.
+pub(crate) struct Control(Signal<CS, ()>);
+
+impl Control {
+    pub(crate) const fn new() -> Self {
+        Self(Signal::new())
+    }
+
+    /// Report a change, after the test set the lines in `Host`.
+    pub(crate) fn change(&self) {
+        self.0.signal(());
+    }
+}
+
+impl ControlLines for Control {
+    async fn changed(&self) {
+        self.0.wait().await
+    }
+}
+
+/// Discards defmt output, which would otherwise need a probe.
+#[cfg(not(feature = "defmt-usb"))]
+#[defmt::global_logger]
+struct DefmtSink;
+
+#[cfg(not(feature = "defmt-usb"))]
+defmt::timestamp!("");
+
+#[cfg(not(feature = "defmt-usb"))]
+unsafe impl defmt::Logger for DefmtSink {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[cfg(test)]
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!()
+}
@@ -0,0 +1,67 @@
+//! What the logger does when its buffer is full, and how much it has lost.
+
+use portable_atomic::{AtomicU32, Ordering};
+
+/// The behaviour of the logger when a record does not fit into its buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Keep what is buffered and drop the new record.
+    #[default]
+    DropNewest,
+    /// Discard the oldest buffered lines until the new record fits.
+    OverwriteOldest,
+    /// Wait for the USB side to make room.
+    ///
+    /// Waiting is only possible from `UsbLogger::log_async`. The synchronous
+    /// `log::Log` path cannot block without stalling the executor that runs the
+    /// logger, so there it behaves like `DropNewest`.
+    Block,
+}
+
+/// Totals of data lost to buffer overflows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DropStats {
+    /// Bytes that never reached the host.
+    pub bytes: u32,
+    /// Records that were lost in full or in part.
+    pub records: u32,
+}
+
+/// Counters shared between the log producers and the marker writer.
+pub(crate) struct DropCounters {
+    bytes: AtomicU32,
+    records: AtomicU32,
+    /// Records dropped since the last "records dropped" marker was written.
+    pending: AtomicU32,
+}
+
+impl DropCounters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            bytes: AtomicU32::new(0),
+            records: AtomicU32::new(0),
+            pending: AtomicU32::new(0),
+        }
+    }
+
+    pub(crate) fn add(&self, bytes: usize, records: u32) {
+        self.bytes.fetch_add(bytes as u32, Ordering::Relaxed);
+        self.records.fetch_add(records, Ordering::Relaxed);
+        self.pending.fetch_add(records, Ordering::Relaxed);
+    }
+
+    pub(crate) fn pending(&self) -> u32 {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn clear_pending(&self, records: u32) {
+        self.pending.fetch_sub(records, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> DropStats {
+        DropStats {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            records: self.records.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Packetisation of the output stream, independent of the USB class.
+
+use embassy_futures::select::{select, Either};
+use embassy_usb::class::cdc_acm::{ControlChanged, Sender};
+use embassy_usb::driver::{Driver, EndpointError};
+
+use crate::link::Link;
+use crate::MAX_PACKET_SIZE;
+
+/// The sending half of a serial port and its control lines.
+pub(crate) trait Port {
+    /// Wait until the device is configured.
+    async fn wait_connection(&mut self);
+
+    /// Send a packet. The packet is only handed to the controller when the
+    /// write completes, so dropping a pending write sends nothing.
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError>;
+
+    fn dtr(&self) -> bool;
+
+    fn rts(&self) -> bool;
+}
+
+impl<'d, D: Driver<'d>> Port for Sender<'d, D> {
+    async fn wait_connection(&mut self) {
+        Sender::wait_connection(self).await
+    }
+
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        Sender::write_packet(self, data).await
+    }
+
+    fn dtr(&self) -> bool {
+        Sender::dtr(self)
+    }
+
+    fn rts(&self) -> bool {
+        Sender::rts(self)
+    }
+}
+
+/// Notifies about changes of the control lines of a `Port`.
+pub(crate) trait ControlLines {
+    async fn changed(&self);
+}
+
+impl ControlLines for ControlChanged<'_> {
+    async fn changed(&self) {
+        self.control_changed().await
+    }
+}
+
+/// The host stopped listening while output was being sent.
+pub(crate) struct Detached;
+
+/// Packs bytes into full packets before sending them to the host, giving up
+/// when the host detaches.
+pub(crate) struct Packets<'s, P: Port, C: ControlLines> {
+    port: &'s mut P,
+    control: &'s C,
+    link: &'s Link,
+    buf: [u8; MAX_PACKET_SIZE as usize],
+    len: usize,
+}
+
+impl<'s, P: Port, C: ControlLines> Packets<'s, P, C> {
+    pub(crate) fn new(port: &'s mut P, control: &'s C, link: &'s Link) -> Self {
+        Self {
+            port,
+            control,
+            link,
+            buf: [0; MAX_PACKET_SIZE as usize],
+            len: 0,
+        }
+    }
+
+    /// Read the control lines and return whether the host is listening.
+    pub(crate) fn attached(&self) -> bool {
+        self.link.set_lines(self.port.dtr(), self.port.rts());
+        self.link.state().attached()
+    }
+
+    pub(crate) async fn changed(&self) {
+        changed(self.control, self.link).await
+    }
+
+    /// Wait until the device is configured, a program on the host has opened
+    /// the port and the bus is not suspended.
+    pub(crate) async fn wait_attached(&mut self) {
+        self.len = 0;
+        self.port.wait_connection().await;
+        while !self.attached() {
+            self.changed().await;
+        }
+    }
+
+    pub(crate) async fn write(&mut self, bytes: &[u8]) -> Result<(), Detached> {
+        for &b in bytes {
+            self.buf[self.len] = b;
+            self.len += 1;
+            if self.len == self.buf.len() {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<(), Detached> {
+        let buf = self.buf;
+        let len = core::mem::take(&mut self.len);
+        if len > 0 {
+            self.send(&buf[..len]).await?;
+        }
+        Ok(())
+    }
+
+    /// Send what is held and then a zero length packet, which the controller
+    /// only takes once the host has read the packet before it, so everything
+    /// sent has arrived when this returns.
+    pub(crate) async fn confirm(&mut self) -> Result<(), Detached> {
+        self.flush().await?;
+        self.send_packet(&[]).await
+    }
+
+    /// Send one packet, and a zero length packet after a full one.
+    pub(crate) async fn send(&mut self, data: &[u8]) -> Result<(), Detached> {
+        self.send_packet(data).await?;
+        if data.len() == MAX_PACKET_SIZE as usize {
+            self.send_packet(&[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a packet, abandoning it if the host closes the port or the bus is
+    /// suspended before the controller takes it.
+    ///
+    /// A pending write has not handed its packet to the controller, so after
+    /// a change that leaves the host attached it is started again without the
+    /// packet ever arriving twice.
+    async fn send_packet(&mut self, data: &[u8]) -> Result<(), Detached> {
+        loop {
+            let changed = changed(self.control, self.link);
+            match select(self.port.write_packet(data), changed).await {
+                Either::First(Ok(())) => return Ok(()),
+                Either::First(Err(_)) => return Err(Detached),
+                Either::Second(()) if !self.attached() => return Err(Detached),
+                Either::Second(()) => {}
+            }
+        }
+    }
+}
+
+/// Wait for the control lines or the suspend state to change.
+async fn changed(control: &impl ControlLines, link: &Link) {
+    select(control.changed(), link.changed()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use embassy_futures::join::join;
+    use embassy_futures::{block_on, yield_now};
+    use embassy_usb::Handler;
+
+    use super::*;
+    use crate::link::LinkHandler;
+    use crate::mock::{Control, Host, MockDriver, SerialPort};
+
+    /// Write `chunks` through `Packets` and return what the host received.
+    fn send(chunks: &[&[u8]], flush: bool) -> (Rc<RefCell<Host>>, bool) {
+        let host = Rc::new(RefCell::new(Host {
+            dtr: true,
+            ..Host::default()
+        }));
+        let mut port = SerialPort::new(&mut MockDriver::new(&host));
+        let link = Link::new();
+        let control = Control::new();
+        let mut packets = Packets::new(&mut port, &control, &link);
+        let ok = block_on(async {
+            for chunk in chunks {
+                packets.write(chunk).await?;
+            }
+            if flush {
+                packets.flush().await?;
+            }
+            Ok::<(), Detached>(())
+        })
+        .is_ok();
+        (host, ok)
+    }
+
+    #[test]
+    fn short_write_is_held_until_flushed() {
+        let (host, _) = send(&[b"hello"], false);
+        assert!(host.borrow().packets.is_empty());
+        let (host, _) = send(&[b"hello"], true);
+        assert_eq!(host.borrow().packets, [b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn writes_are_packed_into_full_packets() {
+        let data: Vec<u8> = (0..150).map(|i| i as u8).collect();
+        let (host, ok) = send(&[&data[..100], &data[100..]], true);
+        assert!(ok);
+        let host = host.borrow();
+        assert_eq!(host.packet_lens(), [64, 0, 64, 0, 22]);
+        assert_eq!(host.packets.concat(), data);
+    }
+
+    #[test]
+    fn full_packet_is_followed_by_zero_length_packet() {
+        let (host, _) = send(&[&[b'x'; 64]], true);
+        assert_eq!(host.borrow().packet_lens(), [64, 0]);
+    }
+
+    #[test]
+    fn short_packet_is_not_followed_by_zero_length_packet() {
+        let (host, _) = send(&[&[b'x'; 63]], true);
+        assert_eq!(host.borrow().packet_lens(), [63]);
+    }
+
+    #[test]
+    fn empty_flush_sends_nothing() {
+        let (host, _) = send(&[], true);
+        assert!(host.borrow().packets.is_empty());
+    }
+
+    #[test]
+    fn failed_write_detaches() {
+        let host = Rc::new(RefCell::new(Host {
+            dtr: true,
+            disabled: true,
+            ..Host::default()
+        }));
+        let mut port = SerialPort::new(&mut MockDriver::new(&host));
+        let link = Link::new();
+        let control = Control::new();
+        let mut packets = Packets::new(&mut port, &control, &link);
+        assert!(block_on(packets.send(b"hello")).is_err());
+    }
+
+    /// Send a packet while the host is not reading, so the one after waits,
+    /// apply `event` and let the host read again, returning the outcome of the
+    /// second packet and what the host got.
+    fn send_stalled(event: impl FnOnce(&Link, &Control, &RefCell<Host>)) -> (bool, Vec<Vec<u8>>) {
+        let host = Rc::new(RefCell::new(Host {
+            dtr: true,
+            stalled: true,
+            ..Host::default()
+        }));
+        let mut port = SerialPort::new(&mut MockDriver::new(&host));
+        let link = Link::new();
+        let control = Control::new();
+        let mut packets = Packets::new(&mut port, &control, &link);
+        let host_fut = async {
+            yield_now().await;
+            event(&link, &control, &host);
+            yield_now().await;
+            host.borrow_mut().stalled = false;
+        };
+        let send = async {
+            packets.send(b"first").await?;
+            packets.send(b"hello").await
+        };
+        let (sent, ()) = block_on(join(send, host_fut));
+        let packets = host.borrow().packets.clone();
+        (sent.is_ok(), packets)
+    }
+
+    #[test]
+    fn packet_is_not_resent_after_a_change() {
+        let (sent, packets) = send_stalled(|link, _, _| LinkHandler(link).suspended(false));
+        assert!(sent);
+        assert_eq!(packets, [b"first".to_vec(), b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn packet_is_abandoned_on_suspend() {
+        let (sent, packets) = send_stalled(|link, _, _| LinkHandler(link).suspended(true));
+        assert!(!sent);
+        assert_eq!(packets.len(), 1);
+    }
+
+    #[test]
+    fn packet_is_abandoned_when_the_port_is_closed() {
+        let (sent, packets) = send_stalled(|_, control, host| {
+            host.borrow_mut().dtr = false;
+            control.change();
+        });
+        assert!(!sent);
+        assert_eq!(packets.len(), 1);
+    }
+
+    #[test]
+    fn attached_follows_dtr() {
+        let host = Rc::new(RefCell::new(Host::default()));
+        let mut port = SerialPort::new(&mut MockDriver::new(&host));
+        let link = Link::new();
+        let control = Control::new();
+        let packets = Packets::new(&mut port, &control, &link);
+        assert!(!packets.attached());
+        host.borrow_mut().dtr = true;
+        assert!(packets.attached());
+        assert!(link.state().dtr);
+    }
+}
@@ -0,0 +1,122 @@
+//! Panic handling: report the panic once, persist it, give the logger a
+//! chance to send it, then halt or reboot.
+//!
+//! Call `handle` from the application's `#[panic_handler]`. The logger can only
+//! drain its buffer while the panicking code spins if it runs at a higher
+//! priority, for example on an `InterruptExecutor`.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use embassy_rp::peripherals::WATCHDOG;
+use embassy_rp::rom_data;
+use embassy_rp::watchdog::Watchdog;
+use portable_atomic::{AtomicBool, AtomicU8, Ordering};
+
+use crate::crashlog;
+
+/// What to do once a panic has been reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum PanicAction {
+    /// Stop, leaving interrupts running so the logger can keep sending.
+    #[default]
+    Halt,
+    /// Reset the chip through the watchdog.
+    WatchdogReboot,
+    /// Reset into the USB mass storage bootloader, ready to be reflashed.
+    Bootsel,
+}
+
+impl PanicAction {
+    /// The action stored as `action as u8`, or `None` for any other value.
+    pub const fn from_u8(action: u8) -> Option<Self> {
+        match action {
+            0 => Some(Self::Halt),
+            1 => Some(Self::WatchdogReboot),
+            2 => Some(Self::Bootsel),
+            _ => None,
+        }
+    }
+}
+
+static ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Set what `handle` does after reporting a panic.
+pub fn set_action(action: PanicAction) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+/// The panic of the previous boot, as read back after the reset.
+#[derive(Debug, Clone, Copy)]
+pub struct PanicReport {
+    /// The formatted panic message, including its location.
+    pub message: &'static str,
+    /// The number of panics since the last power-on reset.
+    pub count: u32,
+}
+
+/// Returns the panic that caused the last reset, if any.
+pub fn previous_panic() -> Option<PanicReport> {
+    crashlog::previous_crash().map(|message| PanicReport {
+        message,
+        count: crashlog::crash_count(),
+    })
+}
+
+/// Report a panic and carry out the configured `PanicAction`.
+pub fn handle(info: &PanicInfo) -> ! {
+    // A panic while reporting a panic goes straight to the action.
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        let mut message = Message {
+            buf: [0; crashlog::CRASH_LEN],
+            len: 0,
+        };
+        let _ = write!(message, "{}", info);
+        let message = message.as_str();
+        crashlog::record_crash(format_args!("{}", message));
+        log::error!("panic: {}", message);
+        log::logger().flush();
+    }
+
+    match PanicAction::from_u8(ACTION.load(Ordering::Relaxed)).unwrap_or_default() {
+        PanicAction::WatchdogReboot => {
+            // Safety: the other core and interrupts keep running and their
+            // owner of the watchdog may still feed or reconfigure it, but
+            // triggering only sets the trigger bit, which neither clears, so
+            // the reset happens regardless.
+            let mut watchdog = Watchdog::new(unsafe { WATCHDOG::steal() });
+            watchdog.trigger_reset();
+        }
+        PanicAction::Bootsel => rom_data::reset_to_usb_boot(0, 0),
+        PanicAction::Halt => {}
+    }
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// The panic message, formatted once into a fixed buffer.
+struct Message {
+    buf: [u8; crashlog::CRASH_LEN],
+    len: usize,
+}
+
+impl Message {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("<invalid panic message>")
+    }
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
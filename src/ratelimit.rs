@@ -0,0 +1,142 @@
+//! Per-callsite rate limiting for `log_ratelimited!`.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::CS;
+
+/// Lets at most one record through per interval and counts the rest.
+pub struct RateLimit {
+    state: Mutex<CS, Cell<State>>,
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    /// When the next record may be logged.
+    next: Instant,
+    /// Records suppressed since the last one was logged.
+    suppressed: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimit {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(Cell::new(State {
+                next: Instant::MIN,
+                suppressed: 0,
+            })),
+        }
+    }
+
+    /// Returns the number of records suppressed since the last one if a
+    /// record may be logged now, or `None` if it must be suppressed.
+    pub fn check(&self, interval: Duration) -> Option<u32> {
+        self.check_at(Instant::now(), interval)
+    }
+
+    fn check_at(&self, now: Instant, interval: Duration) -> Option<u32> {
+        self.state.lock(|state| {
+            let State { next, suppressed } = state.get();
+            if now < next {
+                state.set(State {
+                    next,
+                    suppressed: suppressed.saturating_add(1),
+                });
+                return None;
+            }
+            state.set(State {
+                next: now + interval,
+                suppressed: 0,
+            });
+            Some(suppressed)
+        })
+    }
+}
+
+/// Log a record at most once per interval from this callsite.
+///
+/// The interval is an `embassy_time::Duration`. Records logged within the
+/// interval are counted, and the count is appended to the next record that
+/// gets through.
+///
+/// # Usage
+///
+/// ```
+/// rp2040_project_template::log_ratelimited!(
+///     Duration::from_secs(1),
+///     log::Level::Warn,
+///     "i2c error: {:?}",
+///     err
+/// );
+/// ```
+#[macro_export]
+macro_rules! log_ratelimited {
+    ( $interval:expr, $lvl:expr, $($arg:tt)+ ) => {{
+        static LIMIT: $crate::ratelimit::RateLimit = $crate::ratelimit::RateLimit::new();
+        let lvl = $lvl;
+        if lvl <= ::log::STATIC_MAX_LEVEL && lvl <= ::log::max_level() {
+            match LIMIT.check($interval) {
+                Some(0) => ::log::log!(lvl, $($arg)+),
+                Some(suppressed) => ::log::log!(
+                    lvl,
+                    "{} ({} suppressed)",
+                    format_args!($($arg)+),
+                    suppressed
+                ),
+                None => {}
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_millis(millis)
+    }
+
+    #[test]
+    fn one_record_per_interval_gets_through() {
+        let limit = RateLimit::new();
+        assert_eq!(limit.check_at(at(0), INTERVAL), Some(0));
+        assert_eq!(limit.check_at(at(10), INTERVAL), None);
+        assert_eq!(limit.check_at(at(999), INTERVAL), None);
+        assert_eq!(limit.check_at(at(1000), INTERVAL), Some(2));
+        assert_eq!(limit.check_at(at(1500), INTERVAL), None);
+    }
+
+    #[test]
+    fn suppressed_count_starts_over_after_each_record() {
+        let limit = RateLimit::new();
+        assert_eq!(limit.check_at(at(0), INTERVAL), Some(0));
+        assert_eq!(limit.check_at(at(500), INTERVAL), None);
+        assert_eq!(limit.check_at(at(1000), INTERVAL), Some(1));
+        // The interval runs from the record that got through.
+        assert_eq!(limit.check_at(at(5000), INTERVAL), Some(0));
+    }
+
+    #[test]
+    fn suppressed_count_saturates() {
+        let limit = RateLimit::new();
+        limit.state.lock(|state| {
+            state.set(State {
+                next: at(1000),
+                suppressed: u32::MAX,
+            })
+        });
+        assert_eq!(limit.check_at(at(0), INTERVAL), None);
+        assert_eq!(limit.check_at(at(1000), INTERVAL), Some(u32::MAX));
+    }
+}
@@ -0,0 +1,523 @@
+//! Fan-out of log records to several sinks, each with its own level.
+//!
+//! Register sinks with [`add`] and make [`LOGGER`] the global logger with
+//! [`install`]. Every `log::` call is then passed to all sinks whose level
+//! allows it, so the same logging calls reach a debug probe on the bench and
+//! USB in the field.
+//!
+//! A sink with a filter of its own, like `UsbLogger`, is best added at
+//! `LevelFilter::Trace`, as a record must pass both levels and a more verbose
+//! filter set later would otherwise have no effect.
+//!
+//! ```
+//! static USB: UsbLogger<1024> = UsbLogger::new().with_level(LevelFilter::Info);
+//! static RTT: RttSink = RttSink::new();
+//!
+//! sink::add(&USB, LevelFilter::Trace).unwrap();
+//! sink::add(&RTT, LevelFilter::Debug).unwrap();
+//! sink::install().unwrap();
+//! ```
+
+use core::cell::RefCell;
+
+use embassy_futures::select::select;
+use embassy_net::udp::UdpSocket;
+use embassy_net::IpEndpoint;
+#[cfg(target_os = "none")]
+use embassy_rp::uart::{self, Async, UartTx};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::pipe::Pipe;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Timer};
+use log::{LevelFilter, Metadata, Record, SetLoggerError};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::format::Style;
+use crate::line::Line;
+use crate::overflow::{DropCounters, DropStats};
+use crate::{CS, DRAIN_POLL_MS, MAX_RECORD_LEN};
+
+/// The maximum number of sinks that can be registered.
+pub const MAX_SINKS: usize = 4;
+
+/// A destination for log records.
+pub trait Sink: Sync {
+    /// Whether the sink wants records with this metadata, beyond its level in
+    /// the fan-out.
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    /// Write a record. This is called from `log::Log::log` and must not block.
+    fn write(&self, record: &Record);
+
+    /// The most verbose level the sink can write, given its own filtering.
+    fn max_level(&self) -> LevelFilter {
+        LevelFilter::Trace
+    }
+
+    /// Wait a bounded time for buffered records to be sent.
+    ///
+    /// Sinks drained by a task of their own leave this empty, as busy-waiting
+    /// would keep that task from running. They have an async `flush` instead.
+    fn flush(&self) {}
+}
+
+/// Identifies a registered sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SinkId(usize);
+
+/// Errors returned when registering a sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkError {
+    /// All `MAX_SINKS` slots are in use.
+    Full,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    sink: &'static dyn Sink,
+    level: LevelFilter,
+}
+
+/// A `log::Log` implementation that passes each record on to its sinks.
+pub struct MultiLogger {
+    sinks: Mutex<CS, RefCell<[Option<Entry>; MAX_SINKS]>>,
+}
+
+/// The global fan-out logger.
+pub static LOGGER: MultiLogger = MultiLogger::new();
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+impl MultiLogger {
+    pub(crate) const fn new() -> Self {
+        Self {
+            sinks: Mutex::new(RefCell::new([None; MAX_SINKS])),
+        }
+    }
+
+    /// Register a sink that receives records up to `level`.
+    pub fn add(&self, sink: &'static dyn Sink, level: LevelFilter) -> Result<SinkId, SinkError> {
+        let id = self.sinks.lock(|sinks| {
+            let mut sinks = sinks.borrow_mut();
+            let (i, slot) = sinks
+                .iter_mut()
+                .enumerate()
+                .find(|(_, s)| s.is_none())
+                .ok_or(SinkError::Full)?;
+            *slot = Some(Entry { sink, level });
+            Ok(SinkId(i))
+        })?;
+        self.update_max_level();
+        Ok(id)
+    }
+
+    /// Change the level of a registered sink.
+    pub fn set_level(&self, id: SinkId, level: LevelFilter) {
+        self.sinks.lock(|sinks| {
+            if let Some(entry) = &mut sinks.borrow_mut()[id.0] {
+                entry.level = level;
+            }
+        });
+        self.update_max_level();
+    }
+
+    /// Returns the level of a registered sink.
+    pub fn level(&self, id: SinkId) -> LevelFilter {
+        self.sinks.lock(|sinks| {
+            sinks.borrow()[id.0]
+                .map(|entry| entry.level)
+                .unwrap_or(LevelFilter::Off)
+        })
+    }
+
+    /// A copy of the sink table, so sinks are called without holding the lock.
+    fn entries(&self) -> [Option<Entry>; MAX_SINKS] {
+        self.sinks.lock(|sinks| *sinks.borrow())
+    }
+
+    /// Set the global maximum level to the most verbose level any sink writes.
+    fn update_max_level(&self) {
+        if !INSTALLED.load(Ordering::Relaxed) {
+            return;
+        }
+        let level = self
+            .entries()
+            .iter()
+            .flatten()
+            .map(|entry| entry.level.min(entry.sink.max_level()))
+            .fold(LevelFilter::Off, Ord::max);
+        log::set_max_level(level);
+    }
+}
+
+impl log::Log for MultiLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.entries()
+            .iter()
+            .flatten()
+            .any(|entry| metadata.level() <= entry.level && entry.sink.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        for entry in self.entries().iter().flatten() {
+            if record.level() <= entry.level && entry.sink.enabled(record.metadata()) {
+                entry.sink.write(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for entry in self.entries().iter().flatten() {
+            entry.sink.flush();
+        }
+    }
+}
+
+/// Register a sink with the global fan-out logger.
+pub fn add(sink: &'static dyn Sink, level: LevelFilter) -> Result<SinkId, SinkError> {
+    LOGGER.add(sink, level)
+}
+
+/// Make the fan-out logger the global logger.
+///
+/// This should only be called once, and not together with the `run!` or
+/// `with_class!` macros, which install a `UsbLogger` on its own.
+pub fn install() -> Result<(), SetLoggerError> {
+    // Safety: the critical section keeps other cores and interrupts from
+    // setting a logger at the same time.
+    critical_section::with(|_| unsafe { log::set_logger_racy(&LOGGER) })?;
+    INSTALLED.store(true, Ordering::Relaxed);
+    LOGGER.update_max_level();
+    Ok(())
+}
+
+/// Set the global maximum level after a sink's own filter changed to `level`.
+pub(crate) fn update_max_level(level: LevelFilter) {
+    if INSTALLED.load(Ordering::Relaxed) {
+        LOGGER.update_max_level();
+    } else {
+        log::set_max_level(level);
+    }
+}
+
+/// Writes records to the defmt global logger, which is `defmt-rtt` in this
+/// project unless the `defmt-usb` feature is enabled, at the matching defmt
+/// level.
+pub struct RttSink {
+    style: Style,
+}
+
+impl Default for RttSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RttSink {
+    /// Create a sink writing only the message, as defmt adds the level itself.
+    pub const fn new() -> Self {
+        Self {
+            style: Style::Plain,
+        }
+    }
+
+    /// Set the layout of the records.
+    pub const fn with_style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+}
+
+impl Sink for RttSink {
+    fn write(&self, record: &Record) {
+        let mut line = Line::new();
+        let _ = self.style.write(record, &mut line);
+        let text = line.text();
+        match record.level() {
+            log::Level::Error => defmt::error!("{=str}", text),
+            log::Level::Warn => defmt::warn!("{=str}", text),
+            log::Level::Info => defmt::info!("{=str}", text),
+            log::Level::Debug => defmt::debug!("{=str}", text),
+            log::Level::Trace => defmt::trace!("{=str}", text),
+        }
+    }
+}
+
+/// Writes records to a hardware UART through a buffer of `N` bytes.
+///
+/// The buffer is drained by `run`, which must be running for anything to be
+/// sent.
+#[cfg(target_os = "none")]
+pub struct UartSink<const N: usize> {
+    queue: RecordQueue<N>,
+    style: Style,
+}
+
+#[cfg(target_os = "none")]
+impl<const N: usize> Default for UartSink<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(target_os = "none")]
+impl<const N: usize> UartSink<N> {
+    /// Create a sink using the compact style.
+    pub const fn new() -> Self {
+        Self {
+            queue: RecordQueue::new(),
+            style: Style::Compact,
+        }
+    }
+
+    /// Set the layout of the records.
+    pub const fn with_style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Returns the number of bytes and records lost to buffer overflows so far.
+    pub fn dropped(&self) -> DropStats {
+        self.queue.drops.stats()
+    }
+
+    /// Send buffered records through the UART. Never returns.
+    pub async fn run<T: uart::Instance>(&self, mut tx: UartTx<'_, T, Async>) -> ! {
+        let mut buf = [0; MAX_RECORD_LEN];
+        loop {
+            let len = self.queue.pop(&mut buf).await;
+            let _ = tx.write(&buf[..len]).await;
+            self.queue.done();
+        }
+    }
+
+    /// Wait until everything logged so far has been written to the UART.
+    ///
+    /// Returns false if that did not happen within `timeout`.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        self.queue.flush(timeout).await
+    }
+}
+
+#[cfg(target_os = "none")]
+impl<const N: usize> Sink for UartSink<N> {
+    fn write(&self, record: &Record) {
+        let mut line = Line::new();
+        let _ = self.style.write(record, &mut line);
+        self.queue.push(line.bytes());
+    }
+}
+
+/// Sends each record as one UDP datagram through a buffer of `N` bytes.
+///
+/// The buffer is drained by `run`, which must be running for anything to be
+/// sent.
+pub struct UdpSink<const N: usize> {
+    queue: RecordQueue<N>,
+    style: Style,
+}
+
+impl<const N: usize> Default for UdpSink<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> UdpSink<N> {
+    /// Create a sink using the compact style.
+    pub const fn new() -> Self {
+        Self {
+            queue: RecordQueue::new(),
+            style: Style::Compact,
+        }
+    }
+
+    /// Set the layout of the records.
+    pub const fn with_style(mut self, style: Style) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Returns the number of bytes and records lost to buffer overflows so far.
+    pub fn dropped(&self) -> DropStats {
+        self.queue.drops.stats()
+    }
+
+    /// Send buffered records from `socket` to `remote`. Never returns.
+    ///
+    /// Datagrams that cannot be sent, for example while the link is down,
+    /// are lost.
+    pub async fn run(&self, socket: &UdpSocket<'_>, remote: IpEndpoint) -> ! {
+        self.queue.send_datagrams(socket, remote).await
+    }
+
+    /// Wait until everything logged so far has been handed to the socket.
+    ///
+    /// Returns false if that did not happen within `timeout`.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        self.queue.flush(timeout).await
+    }
+}
+
+impl<const N: usize> Sink for UdpSink<N> {
+    fn write(&self, record: &Record) {
+        let mut line = Line::new();
+        let _ = self.style.write(record, &mut line);
+        self.queue.push(line.bytes());
+    }
+}
+
+/// Whole records waiting to be sent by a sink's task, each stored behind a
+/// two byte length.
+///
+/// The task calls `done` once it has sent the records it took, so `flush` can
+/// tell when they are out.
+pub(crate) struct RecordQueue<const N: usize> {
+    pipe: Pipe<CS, N>,
+    pub(crate) drops: DropCounters,
+    /// Set while records taken from the queue are being sent.
+    sending: AtomicBool,
+    sent: Signal<CS, ()>,
+}
+
+impl<const N: usize> RecordQueue<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            pipe: Pipe::new(),
+            drops: DropCounters::new(),
+            sending: AtomicBool::new(false),
+            sent: Signal::new(),
+        }
+    }
+
+    /// Queue a record if it fits as a whole, otherwise count it as dropped.
+    pub(crate) fn push(&self, record: &[u8]) {
+        let len = (record.len() as u16).to_le_bytes();
+        let fits = critical_section::with(|_| {
+            if self.pipe.free_capacity() < len.len() + record.len() {
+                return false;
+            }
+            self.write_all(&len);
+            self.write_all(record);
+            true
+        });
+        if !fits {
+            self.drops.add(record.len(), 1);
+        }
+    }
+
+    /// Write bytes that are known to fit, across the wraparound if needed.
+    fn write_all(&self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            match self.pipe.try_write(bytes) {
+                Ok(n) => bytes = &bytes[n..],
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Wait for the next record and copy it into `buf`, returning its length.
+    pub(crate) async fn pop(&self, buf: &mut [u8; MAX_RECORD_LEN]) -> usize {
+        let mut len = [0; 2];
+        self.read_exact(&mut len).await;
+        // The contents are still queued, so the queue is not empty until this
+        // is set.
+        self.sending.store(true, Ordering::Relaxed);
+        let len = u16::from_le_bytes(len) as usize;
+        self.read_exact(&mut buf[..len]).await;
+        len
+    }
+
+    /// Copy the next record into `buf` without waiting, returning its length,
+    /// or `None` if there is none.
+    pub(crate) fn try_pop(&self, buf: &mut [u8; MAX_RECORD_LEN]) -> Option<usize> {
+        if self.pipe.is_empty() {
+            return None;
+        }
+        // Records are queued as a whole, so all of one is there once its
+        // length is.
+        self.sending.store(true, Ordering::Relaxed);
+        let mut len = [0; 2];
+        self.read_all(&mut len);
+        let len = u16::from_le_bytes(len) as usize;
+        self.read_all(&mut buf[..len]);
+        Some(len)
+    }
+
+    /// Send each record as one datagram from `socket` to `remote`, dropping
+    /// those that cannot be sent. Never returns.
+    pub(crate) async fn send_datagrams(&self, socket: &UdpSocket<'_>, remote: IpEndpoint) -> ! {
+        let mut buf = [0; MAX_RECORD_LEN];
+        loop {
+            let len = self.pop(&mut buf).await;
+            let _ = socket.send_to(&buf[..len], remote).await;
+            self.done();
+        }
+    }
+
+    /// Note that the records taken so far have been sent.
+    pub(crate) fn done(&self) {
+        self.sending.store(false, Ordering::Relaxed);
+        self.sent.signal(());
+    }
+
+    /// Read bytes that are known to be there, across the wraparound if needed.
+    fn read_all(&self, mut buf: &mut [u8]) {
+        while !buf.is_empty() {
+            match self.pipe.try_read(buf) {
+                Ok(n) => buf = &mut buf[n..],
+                Err(_) => break,
+            }
+        }
+    }
+
+    async fn read_exact(&self, mut buf: &mut [u8]) {
+        while !buf.is_empty() {
+            let n = self.pipe.read(buf).await;
+            buf = &mut buf[n..];
+        }
+    }
+
+    /// Wait until the queue is empty and the sink's task has sent what it
+    /// took, returning false if that did not happen within `timeout`.
+    pub(crate) async fn flush(&self, timeout: Duration) -> bool {
+        let flushed = async {
+            while !self.pipe.is_empty() || self.sending.load(Ordering::Relaxed) {
+                select(self.sent.wait(), Timer::after_millis(DRAIN_POLL_MS)).await;
+            }
+        };
+        with_timeout(timeout, flushed).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_futures::join::join;
+
+    use super::*;
+
+    #[test]
+    fn flush_waits_until_the_taken_record_is_done() {
+        let queue = RecordQueue::<64>::new();
+        queue.push(b"record");
+        let drain = async {
+            let mut buf = [0; MAX_RECORD_LEN];
+            let len = queue.pop(&mut buf).await;
+            assert_eq!(&buf[..len], b"record");
+            assert!(!queue.flush(Duration::from_millis(20)).await);
+            queue.done();
+        };
+        let (_, flushed) = block_on(join(drain, queue.flush(Duration::from_secs(1))));
+        assert!(flushed);
+    }
+
+    #[test]
+    fn flush_times_out_while_nothing_is_taken() {
+        let queue = RecordQueue::<64>::new();
+        queue.push(b"record");
+        assert!(!block_on(queue.flush(Duration::from_millis(20))));
+    }
+}
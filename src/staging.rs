@@ -0,0 +1,405 @@
+//! Lock-free staging of records on their way to the logger buffer.
+//!
+//! Each core has one ring for thread mode and one for each interrupt priority.
+//! Code is only preempted by code of a higher priority, so every ring has at
+//! most one producer at a time, and writing a record into its ring neither
+//! disables interrupts nor takes the lock shared by the cores. Records that do
+//! not fit are counted by the ring as well, and the USB task moves the records
+//! and the counts from all rings into the logger, oldest first.
+
+use core::cell::UnsafeCell;
+
+#[cfg(target_os = "none")]
+use cortex_m::interrupt::InterruptNumber;
+#[cfg(target_os = "none")]
+use cortex_m::peripheral::scb::{Exception, SystemHandler, VectActive};
+#[cfg(target_os = "none")]
+use cortex_m::peripheral::{NVIC, SCB};
+use embassy_time::Instant;
+use portable_atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::MAX_RECORD_LEN;
+
+/// The size of the thread mode rings. A power of two, so positions can wrap.
+const THREAD_STAGING_LEN: usize = 512;
+
+/// The size of the interrupt rings. A power of two, so positions can wrap, and
+/// room for at least two records of `MAX_RECORD_LEN`.
+const INTERRUPT_STAGING_LEN: usize = 1024;
+
+/// The number of interrupt priority levels implemented by the RP2040.
+const PRIORITY_LEVELS: usize = 4;
+
+/// The record length and the low bits of the staging time in front of each
+/// record.
+const HEADER_LEN: usize = 6;
+
+/// The records dropped by the producer of a ring, and the part of them the
+/// consumer has collected.
+///
+/// Only the producer stores the totals and only the consumer stores the
+/// collected counts, so every update is a load and a store. Read-modify-write
+/// atomics would take a critical section on the RP2040.
+struct Drops {
+    bytes: AtomicU32,
+    records: AtomicU32,
+    collected_bytes: AtomicU32,
+    collected_records: AtomicU32,
+}
+
+impl Drops {
+    const fn new() -> Self {
+        Self {
+            bytes: AtomicU32::new(0),
+            records: AtomicU32::new(0),
+            collected_bytes: AtomicU32::new(0),
+            collected_records: AtomicU32::new(0),
+        }
+    }
+
+    fn add(&self, len: usize) {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        self.bytes
+            .store(bytes.wrapping_add(len as u32), Ordering::Relaxed);
+        let records = self.records.load(Ordering::Relaxed);
+        self.records
+            .store(records.wrapping_add(1), Ordering::Relaxed);
+    }
+
+    /// The bytes and records dropped since the last call.
+    fn collect(&self) -> (usize, u32) {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let records = self.records.load(Ordering::Relaxed);
+        let new_bytes = bytes.wrapping_sub(self.collected_bytes.load(Ordering::Relaxed));
+        let new_records = records.wrapping_sub(self.collected_records.load(Ordering::Relaxed));
+        self.collected_bytes.store(bytes, Ordering::Relaxed);
+        self.collected_records.store(records, Ordering::Relaxed);
+        (new_bytes as usize, new_records)
+    }
+}
+
+/// A ring of records with one producer at a time and a single consumer, which
+/// may run on either core.
+pub(crate) struct Staging<const L: usize> {
+    buf: UnsafeCell<[u8; L]>,
+    /// The position of the next record to read, only stored by the consumer.
+    head: AtomicUsize,
+    /// The position of the next record to write, only stored by the producer.
+    tail: AtomicUsize,
+    drops: Drops,
+}
+
+// Safety: bytes between `head` and `tail` are only read by the consumer, and
+// bytes outside them are only written by the producer.
+unsafe impl<const L: usize> Sync for Staging<L> {}
+
+impl<const L: usize> Staging<L> {
+    /// The longest record that fits into the empty ring.
+    const MAX_LEN: usize = if L - HEADER_LEN < MAX_RECORD_LEN {
+        L - HEADER_LEN
+    } else {
+        MAX_RECORD_LEN
+    };
+
+    const fn new() -> Self {
+        Self {
+            buf: UnsafeCell::new([0; L]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            drops: Drops::new(),
+        }
+    }
+
+    fn push(&self, record: &[u8], time: u32) -> bool {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Relaxed);
+        if record.len() > Self::MAX_LEN || L - tail.wrapping_sub(head) < HEADER_LEN + record.len() {
+            return false;
+        }
+        let mut header = [0; HEADER_LEN];
+        header[..2].copy_from_slice(&(record.len() as u16).to_le_bytes());
+        header[2..].copy_from_slice(&time.to_le_bytes());
+        // Safety: both parts are outside the readable part of the ring.
+        unsafe {
+            self.write_at(tail, &header);
+            self.write_at(tail.wrapping_add(HEADER_LEN), record);
+        }
+        let tail = tail.wrapping_add(HEADER_LEN + record.len());
+        self.tail.store(tail, Ordering::Release);
+        true
+    }
+
+    /// The two contiguous spans of `len` bytes at position `pos`, the second
+    /// one at the front of the ring and empty unless they wrap around.
+    fn spans(pos: usize, len: usize) -> ((usize, usize), usize) {
+        let start = pos % L;
+        let first = len.min(L - start);
+        ((start, first), len - first)
+    }
+
+    /// Copy `src` into the ring at position `pos`.
+    ///
+    /// # Safety
+    ///
+    /// The bytes must be outside the readable part of the ring.
+    unsafe fn write_at(&self, pos: usize, src: &[u8]) {
+        let buf = self.buf.get().cast::<u8>();
+        let ((start, first), rest) = Self::spans(pos, src.len());
+        core::slice::from_raw_parts_mut(buf.add(start), first).copy_from_slice(&src[..first]);
+        core::slice::from_raw_parts_mut(buf, rest).copy_from_slice(&src[first..]);
+    }
+
+    /// Copy the bytes at offset `i` from the start of the oldest record into
+    /// `dst`.
+    fn read_at(&self, i: usize, dst: &mut [u8]) {
+        let buf = self.buf.get().cast::<u8>();
+        let pos = self.head.load(Ordering::Relaxed).wrapping_add(i);
+        let ((start, first), rest) = Self::spans(pos, dst.len());
+        // Safety: only called for bytes inside the readable part of the ring.
+        unsafe {
+            dst[..first].copy_from_slice(core::slice::from_raw_parts(buf.add(start), first));
+            dst[first..].copy_from_slice(core::slice::from_raw_parts(buf, rest));
+        }
+    }
+
+    /// The staging time of the oldest record.
+    fn oldest(&self) -> Option<u32> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut time = [0; 4];
+        self.read_at(2, &mut time);
+        Some(u32::from_le_bytes(time))
+    }
+
+    fn len(&self) -> usize {
+        let mut len = [0; 2];
+        self.read_at(0, &mut len);
+        u16::from_le_bytes(len) as usize
+    }
+
+    fn peek(&self, dst: &mut [u8]) {
+        self.read_at(HEADER_LEN, dst);
+    }
+
+    fn pop(&self, len: usize) {
+        let head = self.head.load(Ordering::Relaxed);
+        self.head
+            .store(head.wrapping_add(HEADER_LEN + len), Ordering::Release);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+/// The staging rings of one core.
+pub(crate) struct CoreStaging {
+    thread: Staging<THREAD_STAGING_LEN>,
+    interrupt: [Staging<INTERRUPT_STAGING_LEN>; PRIORITY_LEVELS],
+    /// Records of the NMI and HardFault handlers. The NMI can preempt the
+    /// HardFault handler, so a drop counted by both at once may be lost.
+    unstaged: Drops,
+}
+
+impl CoreStaging {
+    pub(crate) const fn new() -> Self {
+        Self {
+            thread: Staging::new(),
+            interrupt: [
+                Staging::new(),
+                Staging::new(),
+                Staging::new(),
+                Staging::new(),
+            ],
+            unstaged: Drops::new(),
+        }
+    }
+
+    /// Stage a record in the ring of the current execution priority, returning
+    /// false if it does not fit. Must only be called on the owning core.
+    ///
+    /// The NMI and HardFault handlers have no ring, so their records are lost.
+    pub(crate) fn push(&self, record: &[u8]) -> bool {
+        let time = Instant::now().as_ticks() as u32;
+        match priority_level() {
+            Some(None) => self.thread.push(record, time),
+            Some(Some(level)) => self.interrupt[level].push(record, time),
+            None => false,
+        }
+    }
+
+    /// Count a record of `len` bytes that was not staged. Must only be called
+    /// on the owning core.
+    pub(crate) fn drop_record(&self, len: usize) {
+        match priority_level() {
+            Some(None) => self.thread.drops.add(len),
+            Some(Some(level)) => self.interrupt[level].drops.add(len),
+            None => self.unstaged.add(len),
+        }
+    }
+
+    /// The bytes and records dropped on this core since the last call. Must
+    /// only be called by the consumer.
+    pub(crate) fn collect_drops(&self) -> (usize, u32) {
+        core::iter::once(&self.thread.drops)
+            .chain(self.interrupt.iter().map(|s| &s.drops))
+            .chain(core::iter::once(&self.unstaged))
+            .map(Drops::collect)
+            .fold((0, 0), |(b, r), (bytes, records)| (b + bytes, r + records))
+    }
+
+    /// The longest record the ring of the current execution priority takes.
+    pub(crate) fn max_len() -> usize {
+        match priority_level() {
+            Some(None) => Staging::<THREAD_STAGING_LEN>::MAX_LEN,
+            Some(Some(_)) => Staging::<INTERRUPT_STAGING_LEN>::MAX_LEN,
+            None => 0,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.thread.is_empty() && self.interrupt.iter().all(Staging::is_empty)
+    }
+
+    fn rings(&self) -> impl Iterator<Item = Ring<'_>> {
+        core::iter::once(Ring::Thread(&self.thread))
+            .chain(self.interrupt.iter().map(Ring::Interrupt))
+    }
+}
+
+/// One of the rings of a core.
+#[derive(Clone, Copy)]
+pub(crate) enum Ring<'a> {
+    Thread(&'a Staging<THREAD_STAGING_LEN>),
+    Interrupt(&'a Staging<INTERRUPT_STAGING_LEN>),
+}
+
+impl Ring<'_> {
+    fn oldest(self) -> Option<u32> {
+        match self {
+            Ring::Thread(s) => s.oldest(),
+            Ring::Interrupt(s) => s.oldest(),
+        }
+    }
+
+    /// The length of the oldest record.
+    pub(crate) fn len(self) -> usize {
+        match self {
+            Ring::Thread(s) => s.len(),
+            Ring::Interrupt(s) => s.len(),
+        }
+    }
+
+    /// Copy the oldest record into `dst`, which must be as long as the record,
+    /// without removing it.
+    pub(crate) fn peek(self, dst: &mut [u8]) {
+        match self {
+            Ring::Thread(s) => s.peek(dst),
+            Ring::Interrupt(s) => s.peek(dst),
+        }
+    }
+
+    /// Remove the oldest record, which was `len` bytes long.
+    pub(crate) fn pop(self, len: usize) {
+        match self {
+            Ring::Thread(s) => s.pop(len),
+            Ring::Interrupt(s) => s.pop(len),
+        }
+    }
+}
+
+/// The ring holding the oldest staged record of all cores and priorities.
+///
+/// Staging times are compared with wrapping arithmetic, which orders records
+/// correctly as long as none waits for more than half the `u32` tick range.
+pub(crate) fn oldest(cores: &[CoreStaging]) -> Option<Ring<'_>> {
+    cores
+        .iter()
+        .flat_map(CoreStaging::rings)
+        .filter_map(|ring| Some((ring.oldest()?, ring)))
+        .min_by(|(a, _), (b, _)| (a.wrapping_sub(*b) as i32).cmp(&0))
+        .map(|(_, ring)| ring)
+}
+
+/// Whether the running code is in thread mode.
+#[cfg(target_os = "none")]
+pub(crate) fn in_thread_mode() -> bool {
+    SCB::vect_active() == VectActive::ThreadMode
+}
+
+/// Host builds have no interrupt handlers, so everything runs in thread mode.
+#[cfg(not(target_os = "none"))]
+pub(crate) fn in_thread_mode() -> bool {
+    true
+}
+
+/// The priority level of the running code: `Some(None)` in thread mode,
+/// `Some(Some(level))` in a handler with a configurable priority, and `None`
+/// in the NMI and HardFault handlers.
+#[cfg(target_os = "none")]
+fn priority_level() -> Option<Option<usize>> {
+    let priority = match SCB::vect_active() {
+        VectActive::ThreadMode => return Some(None),
+        VectActive::Interrupt { irqn } => NVIC::get_priority(Irq(irqn.into())),
+        VectActive::Exception(Exception::SVCall) => SCB::get_priority(SystemHandler::SVCall),
+        VectActive::Exception(Exception::PendSV) => SCB::get_priority(SystemHandler::PendSV),
+        VectActive::Exception(Exception::SysTick) => SCB::get_priority(SystemHandler::SysTick),
+        VectActive::Exception(_) => return None,
+    };
+    // The RP2040 only implements the top two bits of the priority.
+    Some(Some(priority as usize >> 6))
+}
+
+#[cfg(not(target_os = "none"))]
+fn priority_level() -> Option<Option<usize>> {
+    Some(None)
+}
+
+/// An interrupt number as reported by the active vector.
+#[cfg(target_os = "none")]
+#[derive(Clone, Copy)]
+struct Irq(u16);
+
+// Safety: the number comes from the running handler, so it is a valid interrupt.
+#[cfg(target_os = "none")]
+unsafe impl InterruptNumber for Irq {
+    fn number(self) -> u16 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupt_ring_takes_two_full_records() {
+        let ring = Staging::<INTERRUPT_STAGING_LEN>::new();
+        let record = [b'x'; MAX_RECORD_LEN];
+        assert_eq!(Staging::<INTERRUPT_STAGING_LEN>::MAX_LEN, MAX_RECORD_LEN);
+        assert!(ring.push(&record, 1));
+        assert!(ring.push(&record, 2));
+        assert_eq!(ring.oldest(), Some(1));
+        assert_eq!(ring.len(), MAX_RECORD_LEN);
+        let mut dst = [0; MAX_RECORD_LEN];
+        ring.peek(&mut dst);
+        assert_eq!(dst, record);
+        ring.pop(MAX_RECORD_LEN);
+        assert_eq!(ring.oldest(), Some(2));
+    }
+
+    #[test]
+    fn records_wrap_around_the_end() {
+        let ring = Staging::<16>::new();
+        assert!(ring.push(b"ab", 1));
+        ring.pop(2);
+        // The header fits before the end, the record only partly.
+        assert!(ring.push(b"01234567", 2));
+        assert_eq!(ring.oldest(), Some(2));
+        assert_eq!(ring.len(), 8);
+        let mut dst = [0; 8];
+        ring.peek(&mut dst);
+        assert_eq!(&dst, b"01234567");
+    }
+}
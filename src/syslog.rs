@@ -0,0 +1,230 @@
+//! A log sink sending RFC 5424 syslog messages over UDP.
+//!
+//! Each record becomes one datagram:
+//!
+//! ```text
+//! <14>1 - pico-07 ioexpander - main - portb = 00000000
+//! ```
+//!
+//! The board has no wall clock, so the timestamp is always the nil value `-`.
+//! The target is sent as the MSGID. For a quick test without rsyslog, listen
+//! with `socat -u UDP-RECV:514 -`.
+
+use core::fmt::{self, Write};
+
+use embassy_net::udp::UdpSocket;
+use embassy_net::IpEndpoint;
+use embassy_time::Duration;
+use log::{Level, Record};
+
+use crate::overflow::DropStats;
+use crate::sink::{RecordQueue, Sink};
+use crate::MAX_RECORD_LEN;
+
+/// The standard syslog port.
+pub const SYSLOG_PORT: u16 = 514;
+
+/// The longest HOSTNAME, APP-NAME and MSGID allowed by RFC 5424.
+const MAX_HOSTNAME_LEN: usize = 255;
+const MAX_APP_NAME_LEN: usize = 48;
+const MAX_MSGID_LEN: usize = 32;
+
+/// The syslog facility of the messages, limited to those an application on
+/// the board would use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Facility {
+    #[default]
+    User = 1,
+    Daemon = 3,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// The syslog severity of a log level. Trace and debug are both `debug`.
+pub const fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Sends each record as an RFC 5424 datagram through a buffer of `N` bytes.
+///
+/// The buffer is drained by `run`, which must be running for anything to be
+/// sent.
+pub struct SyslogSink<const N: usize> {
+    queue: RecordQueue<N>,
+    facility: Facility,
+    hostname: Option<&'static str>,
+    app_name: Option<&'static str>,
+}
+
+impl<const N: usize> Default for SyslogSink<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> SyslogSink<N> {
+    /// Create a sink with the user facility and no hostname or app-name.
+    pub const fn new() -> Self {
+        Self {
+            queue: RecordQueue::new(),
+            facility: Facility::User,
+            hostname: None,
+            app_name: None,
+        }
+    }
+
+    /// Set the facility of the messages.
+    pub const fn with_facility(mut self, facility: Facility) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    /// Set the HOSTNAME field, which should not contain spaces.
+    pub const fn with_hostname(mut self, hostname: &'static str) -> Self {
+        self.hostname = Some(hostname);
+        self
+    }
+
+    /// Set the APP-NAME field, which should not contain spaces.
+    pub const fn with_app_name(mut self, app_name: &'static str) -> Self {
+        self.app_name = Some(app_name);
+        self
+    }
+
+    /// Returns the number of bytes and records lost to buffer overflows so far.
+    pub fn dropped(&self) -> DropStats {
+        self.queue.drops.stats()
+    }
+
+    /// Send buffered messages from `socket` to the collector. Never returns.
+    ///
+    /// The collector is normally on `SYSLOG_PORT`. Datagrams that cannot be
+    /// sent, for example while the link is down, are lost.
+    pub async fn run(&self, socket: &UdpSocket<'_>, collector: IpEndpoint) -> ! {
+        self.queue.send_datagrams(socket, collector).await
+    }
+
+    /// Wait until everything logged so far has been handed to the socket.
+    ///
+    /// Returns false if that did not happen within `timeout`.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        self.queue.flush(timeout).await
+    }
+
+    fn format(&self, record: &Record, out: &mut Datagram) -> fmt::Result {
+        let pri = self.facility as u8 * 8 + severity(record.level());
+        write!(out, "<{}>1 - ", pri)?;
+        write_field(out, self.hostname.unwrap_or(""), MAX_HOSTNAME_LEN)?;
+        out.write_char(' ')?;
+        write_field(out, self.app_name.unwrap_or(""), MAX_APP_NAME_LEN)?;
+        out.write_str(" - ")?;
+        write_field(out, record.target(), MAX_MSGID_LEN)?;
+        write!(out, " - {}", record.args())
+    }
+}
+
+impl<const N: usize> Sink for SyslogSink<N> {
+    fn write(&self, record: &Record) {
+        let mut datagram = Datagram {
+            buf: [0; MAX_RECORD_LEN],
+            len: 0,
+        };
+        let _ = self.format(record, &mut datagram);
+        self.queue.push(&datagram.buf[..datagram.len]);
+    }
+}
+
+/// Write a header field of at most `max` characters, replacing anything but
+/// printable ASCII with `_`, or `-` if it is empty.
+fn write_field(out: &mut Datagram, field: &str, max: usize) -> fmt::Result {
+    if field.is_empty() {
+        return out.write_char('-');
+    }
+    for c in field.chars().take(max) {
+        out.write_char(if c.is_ascii_graphic() { c } else { '_' })?;
+    }
+    Ok(())
+}
+
+/// Formats into a fixed buffer, silently truncating at a character boundary.
+struct Datagram {
+    buf: [u8; MAX_RECORD_LEN],
+    len: usize,
+}
+
+impl Write for Datagram {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(MAX_RECORD_LEN - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+
+    /// The datagram `sink` queues for a record.
+    fn datagram(sink: &SyslogSink<512>, level: Level, target: &str) -> String {
+        sink.write(
+            &Record::builder()
+                .args(format_args!("portb = {:08b}", 5))
+                .level(level)
+                .target(target)
+                .build(),
+        );
+        let mut buf = [0; MAX_RECORD_LEN];
+        let len = sink.queue.try_pop(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn record_is_formatted_as_rfc_5424() {
+        let sink = SyslogSink::new()
+            .with_hostname("pico-07")
+            .with_app_name("ioexpander");
+        assert_eq!(
+            datagram(&sink, Level::Info, "main"),
+            "<14>1 - pico-07 ioexpander - main - portb = 00000101"
+        );
+    }
+
+    #[test]
+    fn pri_combines_facility_and_severity() {
+        let sink = SyslogSink::new().with_facility(Facility::Local0);
+        assert!(datagram(&sink, Level::Warn, "main").starts_with("<132>1 "));
+        assert!(datagram(&sink, Level::Trace, "main").starts_with("<135>1 "));
+    }
+
+    #[test]
+    fn missing_fields_are_nil_and_others_are_sanitized() {
+        let sink = SyslogSink::new().with_hostname("pico 07");
+        let long = "m".repeat(40);
+        assert_eq!(
+            datagram(&sink, Level::Error, &long),
+            std::format!("<11>1 - pico_07 - - {} - portb = 00000101", &long[..32])
+        );
+        assert_eq!(
+            datagram(&sink, Level::Error, ""),
+            "<11>1 - pico_07 - - - - portb = 00000101"
+        );
+    }
+}
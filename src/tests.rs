@@ -0,0 +1,370 @@
+//! Tests of the buffering, overflow handling and flushing of `UsbLogger`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::string::String;
+use std::vec::Vec;
+
+use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
+use embassy_futures::{block_on, yield_now};
+use embassy_time::Duration;
+use log::{Level, LevelFilter, Log, Record};
+
+use crate::format::Style;
+use crate::frame::{self, Encoding};
+use crate::link::Link;
+use crate::mock::{Control, Host, MockDriver, SerialPort};
+use crate::overflow::{DropStats, OverflowPolicy};
+use crate::packets::Packets;
+use crate::sink::MultiLogger;
+use crate::{UsbLogger, MAX_RECORD_LEN};
+
+fn log(logger: &UsbLogger<64>, message: &str) {
+    logger.log(
+        &Record::builder()
+            .args(format_args!("{}", message))
+            .level(Level::Info)
+            .build(),
+    );
+}
+
+/// Everything in the logger's buffer.
+fn buffered<const N: usize>(logger: &UsbLogger<N>) -> String {
+    let mut out = Vec::new();
+    while let Some(grant) = logger.buffer.read() {
+        out.extend_from_slice(grant.buf());
+        let len = grant.buf().len();
+        grant.release(len);
+    }
+    String::from_utf8(out).unwrap()
+}
+
+fn write<const N: usize>(logger: &UsbLogger<N>, b: &[u8]) -> bool {
+    logger.write_all_or_nothing(b.len(), |dst| dst.copy_from_slice(b))
+}
+
+/// Log ten ten-byte records into a buffer with room for six of them.
+fn overflow(policy: OverflowPolicy) -> UsbLogger<64> {
+    let logger = UsbLogger::new()
+        .with_style(Style::Plain)
+        .with_overflow_policy(policy);
+    for i in 0..10 {
+        log(&logger, &std::format!("record {}", i));
+    }
+    assert!(logger.drain());
+    logger
+}
+
+#[test]
+fn records_are_staged_until_drained() {
+    let logger = UsbLogger::<64>::new().with_style(Style::Plain);
+    log(&logger, "hello");
+    assert_eq!(buffered(&logger), "");
+    assert!(logger.drain());
+    assert_eq!(buffered(&logger), "hello\r\n");
+}
+
+#[test]
+fn drop_newest_keeps_the_buffered_records() {
+    let logger = overflow(OverflowPolicy::DropNewest);
+    let expected: String = (0..6).map(|i| std::format!("record {}\r\n", i)).collect();
+    assert_eq!(buffered(&logger), expected);
+    assert_eq!(
+        logger.dropped(),
+        DropStats {
+            bytes: 40,
+            records: 4
+        }
+    );
+}
+
+#[test]
+fn overwrite_oldest_discards_whole_lines() {
+    let logger = overflow(OverflowPolicy::OverwriteOldest);
+    let expected: String = (4..10).map(|i| std::format!("record {}\r\n", i)).collect();
+    assert_eq!(buffered(&logger), expected);
+    assert_eq!(logger.dropped().records, 4);
+}
+
+/// Undo the COBS encoding of a frame without its terminating zero.
+fn cobs_decode(src: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < src.len() {
+        let code = src[i] as usize;
+        out.extend_from_slice(&src[i + 1..i + code]);
+        i += code;
+        if code < 0xff && i < src.len() {
+            out.push(0);
+        }
+    }
+    out
+}
+
+#[test]
+fn overwrite_oldest_discards_whole_frames() {
+    let logger = UsbLogger::<256>::new()
+        .with_encoding(Encoding::Cobs)
+        .with_overflow_policy(OverflowPolicy::OverwriteOldest);
+    for i in 0..20 {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("line {}\nof a frame", i))
+                .level(Level::Info)
+                .target("t")
+                .build(),
+        );
+        assert!(logger.drain());
+    }
+    let mut out = Vec::new();
+    while let Some(grant) = logger.buffer.read() {
+        out.extend_from_slice(grant.buf());
+        let len = grant.buf().len();
+        grant.release(len);
+    }
+    assert_eq!(out.last(), Some(&0));
+    // The frames left are whole, and apart from drop markers they are the
+    // newest records.
+    let messages: Vec<String> = out[..out.len() - 1]
+        .split(|&b| b == 0)
+        .filter_map(|frame| {
+            let frame = cobs_decode(frame);
+            assert_eq!(frame[0], frame::VERSION);
+            let target_len = frame[15] as usize;
+            let message = String::from_utf8(frame[16 + target_len..].to_vec()).unwrap();
+            (&frame[16..16 + target_len] == b"t").then_some(message)
+        })
+        .collect();
+    let kept = messages.len();
+    assert!(kept > 1 && kept < 20);
+    let expected: Vec<String> = (20 - kept..20)
+        .map(|i| std::format!("line {}\nof a frame", i))
+        .collect();
+    assert_eq!(messages, expected);
+    // The drop markers that were discarded count as well.
+    assert!(logger.dropped().records as usize >= 20 - kept);
+}
+
+#[test]
+fn drop_marker_is_written_once_there_is_room() {
+    let logger = overflow(OverflowPolicy::DropNewest);
+    buffered(&logger);
+    log(&logger, "after");
+    assert!(logger.drain());
+    assert_eq!(buffered(&logger), "[4 records dropped]\r\nafter\r\n");
+}
+
+#[test]
+fn block_leaves_the_record_staged() {
+    let logger = UsbLogger::<64>::new()
+        .with_style(Style::Plain)
+        .with_overflow_policy(OverflowPolicy::Block);
+    for i in 0..7 {
+        log(&logger, &std::format!("record {}", i));
+    }
+    assert!(!logger.drain());
+    assert_eq!(logger.dropped().records, 0);
+    buffered(&logger);
+    assert!(logger.drain());
+    assert_eq!(buffered(&logger), "record 6\r\n");
+}
+
+#[test]
+fn record_wraps_to_the_front_instead_of_being_split() {
+    let logger = UsbLogger::<16>::new();
+    assert!(write(&logger, b"0123456789"));
+    logger.buffer.read().unwrap().release(8);
+    // Only six bytes fit before the end, but eight are free at the front.
+    assert!(write(&logger, b"abcdefgh"));
+    assert_eq!(buffered(&logger), "89abcdefgh");
+}
+
+#[test]
+fn record_that_does_not_fit_is_not_split() {
+    let logger = UsbLogger::<16>::new();
+    assert!(write(&logger, b"0123456789"));
+    assert!(!write(&logger, b"abcdefghij"));
+    assert_eq!(buffered(&logger), "0123456789");
+}
+
+#[test]
+fn long_record_is_truncated() {
+    let logger = UsbLogger::<512>::new().with_style(Style::Plain);
+    let message = "x".repeat(300);
+    logger.log(
+        &Record::builder()
+            .args(format_args!("{}", message))
+            .level(Level::Info)
+            .build(),
+    );
+    assert!(logger.drain());
+    let line = buffered(&logger);
+    assert_eq!(line.len(), MAX_RECORD_LEN);
+    assert!(line.ends_with("x...\r\n"));
+}
+
+#[test]
+fn repeated_records_are_collapsed() {
+    let logger = UsbLogger::<64>::new()
+        .with_style(Style::Plain)
+        .with_dedup(embassy_time::Duration::from_secs(60));
+    for _ in 0..3 {
+        log(&logger, "same");
+    }
+    log(&logger, "other");
+    assert!(logger.drain());
+    assert_eq!(
+        buffered(&logger),
+        "same\r\n[last message repeated 2 times]\r\nother\r\n"
+    );
+}
+
+#[test]
+fn per_target_levels_apply_below_the_most_verbose_level() {
+    let logger = UsbLogger::<64>::new();
+    logger.set_filter("warn,noisy=off,chatty=debug").unwrap();
+    let enabled = |level, target| {
+        logger.enabled(&log::Metadata::builder().level(level).target(target).build())
+    };
+    assert!(enabled(Level::Debug, "chatty"));
+    assert!(!enabled(Level::Trace, "chatty"));
+    assert!(!enabled(Level::Info, "main"));
+    assert!(!enabled(Level::Error, "noisy"));
+    logger.set_filter("info").unwrap();
+    assert!(enabled(Level::Info, "noisy"));
+    assert!(!enabled(Level::Debug, "chatty"));
+}
+
+#[test]
+fn records_that_do_not_fit_into_their_staging_ring_are_counted() {
+    let logger = UsbLogger::<4096>::new().with_style(Style::Plain);
+    for i in 0..100 {
+        logger.log(
+            &Record::builder()
+                .args(format_args!("record {:02}", i))
+                .level(Level::Info)
+                .build(),
+        );
+    }
+    assert!(logger.drain());
+    let staged = buffered(&logger).lines().count() as u32;
+    let dropped = logger.dropped();
+    assert!(staged < 100);
+    assert_eq!(dropped.records, 100 - staged);
+    assert_eq!(dropped.bytes, dropped.records * 11);
+}
+
+#[test]
+fn filter_set_at_runtime_applies_through_the_fan_out() {
+    static USB: UsbLogger<64> = UsbLogger::new()
+        .with_style(Style::Plain)
+        .with_level(LevelFilter::Info);
+    let fan_out = MultiLogger::new();
+    fan_out.add(&USB, LevelFilter::Trace).unwrap();
+    let debug = || {
+        fan_out.log(
+            &Record::builder()
+                .args(format_args!("pins"))
+                .level(Level::Debug)
+                .target("mcp")
+                .build(),
+        )
+    };
+    debug();
+    assert!(USB.drain());
+    assert_eq!(buffered(&USB), "");
+    USB.set_filter("info,mcp=debug").unwrap();
+    debug();
+    assert!(USB.drain());
+    assert_eq!(buffered(&USB), "pins\r\n");
+}
+
+/// A logger with "hello" logged, and a port the host has opened.
+fn attached() -> (UsbLogger<64>, Rc<RefCell<Host>>) {
+    let logger = UsbLogger::<64>::new().with_style(Style::Plain);
+    log(&logger, "hello");
+    let host = Rc::new(RefCell::new(Host {
+        dtr: true,
+        ..Host::default()
+    }));
+    (logger, host)
+}
+
+#[test]
+fn flush_waits_until_the_host_has_read_the_output() {
+    let (logger, host) = attached();
+    host.borrow_mut().stalled = true;
+    let mut port = SerialPort::new(&mut MockDriver::new(&host));
+    let link = Link::new();
+    let control = Control::new();
+    let mut packets = Packets::new(&mut port, &control, &link);
+    let drain = async {
+        loop {
+            logger.drain();
+            yield_now().await;
+        }
+    };
+    let session = join(drain, logger.run_session(&mut packets, 0));
+    let flush = async {
+        // The output is in the controller, but the host does not read it.
+        assert!(!logger.flush(Duration::from_millis(20)).await);
+        host.borrow_mut().stalled = false;
+        logger.flush(Duration::from_secs(1)).await
+    };
+    let flushed = block_on(select(session, flush));
+    assert!(matches!(flushed, Either::Second(true)));
+    let host = host.borrow();
+    let output = String::from_utf8(host.packets.concat()).unwrap();
+    assert!(output.ends_with("hello\r\n"));
+    // The packet confirming that the host read the output.
+    assert_eq!(host.packet_lens().last(), Some(&0));
+}
+
+#[test]
+fn flush_times_out_while_nothing_is_sent() {
+    let (logger, _) = attached();
+    assert!(!block_on(logger.flush(Duration::from_millis(20))));
+}
+
+#[test]
+fn closing_the_port_during_a_stalled_write_ends_the_session() {
+    let (logger, host) = attached();
+    let mut port = SerialPort::new(&mut MockDriver::new(&host));
+    let link = Link::new();
+    let control = Control::new();
+    let mut packets = Packets::new(&mut port, &control, &link);
+    let drain = async {
+        loop {
+            logger.drain();
+            yield_now().await;
+        }
+    };
+    let host_fut = async {
+        // Let the replay through, then stop reading with a packet in the
+        // controller, so the next one waits.
+        for _ in 0..10 {
+            yield_now().await;
+        }
+        host.borrow_mut().stalled = true;
+        log(&logger, "unread");
+        for _ in 0..10 {
+            yield_now().await;
+        }
+        host.borrow_mut().dtr = false;
+        control.change();
+        core::future::pending::<()>().await
+    };
+    let session = select(logger.run_session(&mut packets, 0), drain);
+    let ended = block_on(select(session, host_fut));
+    assert!(matches!(ended, Either::First(Either::First(Err(_)))));
+    assert_eq!(
+        logger.dropped(),
+        DropStats {
+            bytes: 8,
+            records: 1
+        }
+    );
+    let output = String::from_utf8(host.borrow().packets.concat()).unwrap();
+    assert!(!output.contains("unread"));
+}